@@ -0,0 +1,113 @@
+//! Micro-benchmark of broadcasting one message to every member of a room.
+//!
+//! Joins `BENCH_SESSIONS` (default `10000`) stand-in sessions to a `ChatRoom`, broadcasts
+//! `BENCH_ROUNDS` (default `50`) payloads of `BENCH_PAYLOAD` bytes (default `1024`) and reports
+//! the time and heap allocations each broadcast costs.
+//!
+//! ```text
+//! cargo bench --bench fanout
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use realtime_chatrooms::{config, outbound, room, server};
+
+/// Counts every allocation so the cost of a broadcast can be measured in bytes, not just time
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Stands in for a `WsChatSession`, counting the frames it drains from its queue
+struct Sink {
+    received: Arc<AtomicUsize>,
+    sender: outbound::SessionSender,
+}
+
+impl Actor for Sink {
+    type Context = Context<Self>;
+}
+
+impl Handler<outbound::Drain> for Sink {
+    type Result = ();
+
+    fn handle(&mut self, _msg: outbound::Drain, _ctx: &mut Self::Context) -> Self::Result {
+        let drained = self.sender.drain();
+        self.received.fetch_add(drained.frames.len(), Ordering::Relaxed);
+    }
+}
+
+fn env_or(key: &str, default: usize) -> usize {
+    env::var(key).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+async fn wait_for(received: &AtomicUsize, target: usize) {
+    while received.load(Ordering::Relaxed) < target {
+        actix_rt::time::sleep(Duration::from_micros(50)).await;
+    }
+}
+
+#[actix_rt::main]
+async fn main() {
+    let sessions = env_or("BENCH_SESSIONS", 10_000);
+    let rounds = env_or("BENCH_ROUNDS", 50);
+    let payload_size = env_or("BENCH_PAYLOAD", 1024);
+
+    let received = Arc::new(AtomicUsize::new(0));
+    let room = room::ChatRoom::new("bench").start();
+
+    for id in 0..sessions {
+        Sink::create(|ctx| {
+            let sender = outbound::SessionSender::new(ctx.address().recipient(), config::OutboundConfig::default());
+            room.do_send(room::Join { id, addr: sender.clone() });
+
+            Sink { received: received.clone(), sender }
+        });
+    }
+
+    let payload = "x".repeat(payload_size);
+
+    // Warm up mailboxes before measuring
+    room.do_send(room::Broadcast { msg: server::Message::new(payload.clone()), skip_id: usize::MAX });
+    wait_for(&received, sessions).await;
+    received.store(0, Ordering::Relaxed);
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    let started = Instant::now();
+
+    for round in 1..=rounds {
+        room.do_send(room::Broadcast { msg: server::Message::new(payload.clone()), skip_id: usize::MAX });
+        wait_for(&received, sessions * round).await;
+    }
+
+    let elapsed = started.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed) - allocated_bytes;
+
+    println!("{rounds} broadcasts of {payload_size} bytes to {sessions} sessions");
+    println!("  per broadcast:   {:.2?}", elapsed / rounds as u32);
+    println!("  per delivery:    {:.0?}", elapsed / (rounds * sessions) as u32);
+    println!("  allocations:     {} per broadcast", allocations / rounds);
+    println!("  allocated bytes: {} per broadcast", allocated_bytes / rounds);
+}
@@ -0,0 +1,99 @@
+//! Load benchmark for the HTTP routes.
+//!
+//! Fires requests at a running server from a fixed number of concurrent clients and reports
+//! throughput and latency percentiles. Start the server first, then run
+//!
+//! ```text
+//! cargo bench --bench routes_load
+//! ```
+//!
+//! Configured through environment variables:
+//!
+//! * `BENCH_URL` - base URL of the server, defaults to `http://127.0.0.1:8080`
+//! * `BENCH_PATHS` - comma separated paths to request in turn, defaults to `/rooms`
+//! * `BENCH_REQUESTS` - total number of requests, defaults to `10000`
+//! * `BENCH_CONCURRENCY` - number of requests in flight at once, defaults to `64`
+
+use std::env;
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+#[actix_rt::main]
+async fn main() {
+    let base = env::var("BENCH_URL").unwrap_or_else(|_| "http://127.0.0.1:8080".to_owned());
+    let paths: Vec<String> = env::var("BENCH_PATHS")
+        .unwrap_or_else(|_| "/rooms".to_owned())
+        .split(',')
+        .map(|path| path.trim().to_owned())
+        .collect();
+    let requests: usize = env_or("BENCH_REQUESTS", 10_000);
+    let concurrency: usize = env_or("BENCH_CONCURRENCY", 64).max(1);
+
+    let client = awc::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .connector(awc::Connector::new().limit(concurrency))
+        .finish();
+
+    // Make sure the server is up before timing anything
+    if let Err(err) = client.get(format!("{base}{}", paths[0])).send().await {
+        println!("{err} Could not reach {base}, is the server running?");
+        return;
+    }
+
+    let started = Instant::now();
+
+    let workers = (0..concurrency).map(|worker| {
+        let client = client.clone();
+        let base = base.clone();
+        let paths = paths.clone();
+
+        async move {
+            let mut latencies = Vec::new();
+            let mut errors = 0usize;
+
+            for n in (worker..requests).step_by(concurrency) {
+                let url = format!("{base}{}", paths[n % paths.len()]);
+                let sent = Instant::now();
+
+                match client.get(url).send().await {
+                    Ok(mut res) if !res.status().is_server_error() => {
+                        let _ = res.body().limit(16 * 1024 * 1024).await;
+                        latencies.push(sent.elapsed());
+                    }
+                    _ => errors += 1,
+                }
+            }
+
+            (latencies, errors)
+        }
+    });
+
+    let results = join_all(workers).await;
+    let elapsed = started.elapsed();
+
+    let mut latencies: Vec<Duration> = Vec::with_capacity(requests);
+    let mut errors = 0;
+    for (worker_latencies, worker_errors) in results {
+        latencies.extend(worker_latencies);
+        errors += worker_errors;
+    }
+    latencies.sort();
+
+    let percentile = |p: f64| {
+        if latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        latencies[((latencies.len() - 1) as f64 * p) as usize]
+    };
+
+    println!("{requests} requests to {base}{paths:?} with {concurrency} concurrent clients");
+    println!("  elapsed:    {elapsed:.2?}");
+    println!("  throughput: {:.1} req/s", latencies.len() as f64 / elapsed.as_secs_f64());
+    println!("  errors:     {errors}");
+    println!("  latency:    p50 {:.2?}  p90 {:.2?}  p99 {:.2?}", percentile(0.5), percentile(0.9), percentile(0.99));
+}
@@ -85,8 +85,9 @@ export default function Login({ show, setAuth }) {
         const onSignIn = async (e) => {
             e.preventDefault();
             let username = e.target.username.value;
+            let token = e.target.token.value;
 
-            if (username === "") {
+            if (username === "" || token === "") {
                 return;
             }
 
@@ -102,7 +103,8 @@ export default function Login({ show, setAuth }) {
                 return;
             }
 
-            setAuth(res)
+            // The token is only shown when the account is created, or issued again by an operator
+            setAuth({ ...res, token })
         }
 
         return (
@@ -112,6 +114,11 @@ export default function Login({ show, setAuth }) {
                     <input required type="text" name="username" placeholder="John Doe"
                         className="w-full px-4 py-2 mt-2 border rounded-md focus:outline-none focus:ring-1 focus:ring-blue-600" />
                 </div>
+                <div>
+                    <label className="text-sm font-light">Access token</label>
+                    <input required type="password" name="token"
+                        className="w-full px-4 py-2 mt-2 border rounded-md focus:outline-none focus:ring-1 focus:ring-blue-600" />
+                </div>
                 <div className="flex items-baseline justify-between">
                     <button type="submit"
                         className="px-6 py-2 mt-4 text-white bg-violet-600 rounded-lg hover:bg-violet-700 w-full">Submit</button>
@@ -1,42 +1,36 @@
 import { useEffect, useRef } from "react";
 
-export default function useWebsocket(onMessage) {
+export default function useWebsocket(onMessage, token) {
     const ws = useRef(null);
+    const handler = useRef(onMessage);
+    handler.current = onMessage;
 
     useEffect(() => {
-        if (ws.current !== null) {
+        // The server takes who we are from the token, frames from a socket without one are refused
+        if (!token) {
             return;
         }
 
-        const wsUri = 'ws://localhost:8080/ws';
+        const wsUri = 'ws://localhost:8080/ws?token=' + encodeURIComponent(token);
 
         ws.current = new WebSocket(wsUri);
         ws.current.onopen = () => console.log("ws opened");
         ws.current.onclose = () => console.log("ws closed");
-
-
+        ws.current.onmessage = e => {
+            handler.current(e.data);
+        };
 
         const wsCurrent = ws.current;
 
         return () => {
+            ws.current = null;
             wsCurrent.close();
         };
 
-    }, []);
-
-    useEffect(() => {
-        if (!ws.current) {
-            return;
-        }
-
-        ws.current.onmessage = e => {
-            onMessage(e.data);
-        };
-
-    }, []);
+    }, [token]);
 
     const sendMessage = (msg) => {
-        if (!ws.current) {
+        if (!ws.current || ws.current.readyState !== WebSocket.OPEN) {
             return;
         }
 
@@ -44,4 +38,4 @@ export default function useWebsocket(onMessage) {
     }
 
     return sendMessage;
-}
\ No newline at end of file
+}
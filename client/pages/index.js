@@ -15,8 +15,9 @@ export default function Home() {
     const [auth, setAuthUser] = useLocalStorage("user", false);
     const [isLoading, messages, setMessages, fetchConversations] = useConversations("");
 
-    const handleTyping = (mode) => {
-        if (mode === "IN") {
+    const handleTyping = (userIds) => {
+        // The server sends everyone typing in the room, including this user
+        if (userIds.some(userId => userId !== auth._id)) {
             setIsTyping(true);
         }
 
@@ -37,7 +38,7 @@ export default function Home() {
             let messageData = JSON.parse(data);
             switch (messageData.chat_type) {
                 case "TYPING": {
-                    handleTyping(messageData.value[0]);
+                    handleTyping(messageData.value);
                     return;
                 }
 
@@ -53,7 +54,7 @@ export default function Home() {
         }
     }
 
-    const sendMessage = useWebsocket(onMessage);
+    const sendMessage = useWebsocket(onMessage, auth.token);
 
     const updateFocus = () => {
         const data = {
@@ -110,6 +111,17 @@ export default function Home() {
         console.log("Wasnt null!"); 
         fetchConversations(data._id);
         setSelectedRoom(data);
+
+        // The server sends messages to, and moderates, the room the socket has joined
+        const join = {
+            id: 0,
+            chat_type: "TEXT",
+            value: ["/join " + data._id],
+            room_id: data._id,
+            user_id: auth._id
+        }
+
+        sendMessage(JSON.stringify(join));
     }
 
     const signOut = () => {
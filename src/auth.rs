@@ -0,0 +1,112 @@
+use std::env;
+use std::fmt::Write;
+
+use actix_web::http::header::{HeaderMap, AUTHORIZATION, ORIGIN};
+use actix_web::HttpRequest;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use crate::config::CorsConfig;
+use crate::database::{Database, DbError};
+
+const ADMIN_TOKEN_KEY: &str = "ADMIN_TOKEN";
+/// Query parameter carrying the access token of a WebSocket handshake, browsers cannot set headers on one
+const TOKEN_PARAM: &str = "token";
+
+/// Returns the token of an `Authorization: Bearer <token>` header.
+///
+/// Users are given an access token when their account is created, see `identify` for turning it
+/// back into a user.
+pub fn bearer(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let token = value.strip_prefix("Bearer ")?.trim();
+
+    if token.is_empty() {
+        return None;
+    }
+
+    Some(token.to_owned())
+}
+
+/// Returns the access token of a WebSocket handshake, from the `Authorization` header or the `token` query parameter
+pub fn handshake_token(req: &HttpRequest) -> Option<String> {
+    if let Some(token) = bearer(req.headers()) {
+        return Some(token);
+    }
+
+    req.query_string()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == TOKEN_PARAM)
+        .map(|(_, token)| token.trim().to_owned())
+        .filter(|token| !token.is_empty())
+}
+
+/// Resolves the user an access token was issued to, `None` if there is no token or it is not known
+pub async fn identify(db: &Database, token: Option<&str>) -> Result<Option<String>, DbError> {
+    let Some(token) = token else {
+        return Ok(None);
+    };
+
+    db.find_token_user(&hash_token(token)).await
+}
+
+/// Generates a new access token, 32 random bytes hex encoded
+pub fn new_token() -> String {
+    hex(&rand::thread_rng().gen::<[u8; 32]>())
+}
+
+/// The hash a token is stored and looked up under, so a leaked database does not leak working tokens
+pub fn hash_token(token: &str) -> String {
+    hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut out, byte| {
+        let _ = write!(out, "{byte:02x}");
+        out
+    })
+}
+
+/// Whether the request carries the operator token set in the `ADMIN_TOKEN` environment variable
+pub fn is_admin(headers: &HeaderMap) -> bool {
+    let Ok(expected) = env::var(ADMIN_TOKEN_KEY) else {
+        return false;
+    };
+
+    match bearer(headers) {
+        Some(token) if !expected.is_empty() => constant_time_eq(token.as_bytes(), expected.as_bytes()),
+        _ => false,
+    }
+}
+
+/// Checks the `Origin` of a WebSocket handshake against the CORS allow-list.
+///
+/// Browsers attach cookies and other ambient credentials to cross-site WebSocket connections
+/// without any preflight, so the origin is the only thing telling a hijacking page apart.
+pub fn check_origin(headers: &HeaderMap, cors: &CorsConfig) -> Result<(), String> {
+    let Some(origin) = headers.get(ORIGIN) else {
+        return if cors.allow_missing_origin {
+            Ok(())
+        } else {
+            Err("the Origin header is missing".to_owned())
+        };
+    };
+
+    let origin = origin.to_str().map_err(|_| "the Origin header is not valid text".to_owned())?;
+
+    if cors.allows(origin) {
+        Ok(())
+    } else {
+        Err(format!("origin {origin} is not allowed"))
+    }
+}
+
+/// Compares two byte strings without returning early on the first mismatch
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,293 @@
+use actix::prelude::*;
+use actix_web_actors::ws;
+use serde_json::json;
+
+use crate::session::{ChatMessage, ChatType, WsChatSession};
+use crate::{models, moderation, server, session};
+
+/// Longest nickname `/nick` accepts, in characters
+const MAX_NICKNAME: usize = 32;
+
+/// A parsed slash command, as sent in a TEXT frame
+#[derive(Debug, Clone)]
+pub struct Invocation {
+    pub room_id: String,
+    pub user_id: String,
+    /// Everything after the command name, trimmed
+    pub rest: String,
+    /// `rest` split on whitespace
+    pub args: Vec<String>,
+}
+
+pub type CommandFn = fn(&mut WsChatSession, Invocation, &mut ws::WebsocketContext<WsChatSession>);
+
+/// A slash command and the help shown for it by `/help`
+#[derive(Debug)]
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub summary: &'static str,
+    pub run: CommandFn,
+}
+
+/// The slash commands a chat session understands.
+///
+/// `Registry::default()` holds the built in commands, more can be added with `register` before
+/// the registry is handed to the sessions.
+#[derive(Debug)]
+pub struct Registry {
+    commands: Vec<Command>,
+}
+
+impl Registry {
+    /// A registry without any commands
+    pub fn empty() -> Self {
+        Registry { commands: Vec::new() }
+    }
+
+    /// Adds a command, replacing any command with the same name
+    pub fn register(&mut self, command: Command) {
+        self.commands.retain(|existing| existing.name != command.name);
+        self.commands.push(command);
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Command> {
+        self.commands.iter().find(|command| command.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Registry::empty();
+
+        registry.register(Command { name: "nick", usage: "/nick <nickname>", summary: "Change your nickname", run: nick });
+        registry.register(Command { name: "join", usage: "/join [room]", summary: "Join a room, or list the active rooms", run: join });
+        registry.register(Command { name: "leave", usage: "/leave", summary: "Go back to the lobby", run: leave });
+        registry.register(Command { name: "who", usage: "/who", summary: "List the users in this room", run: who });
+        registry.register(Command { name: "me", usage: "/me <action>", summary: "Describe what you are doing", run: me });
+        registry.register(Command { name: "topic", usage: "/topic [topic]", summary: "Show the room's topic, or set it as a moderator", run: topic });
+        registry.register(Command { name: "help", usage: "/help", summary: "List the available commands", run: help });
+
+        registry
+    }
+}
+
+/// Splits a message into a command name and its arguments if it starts with a single `/`
+pub fn parse(text: &str) -> Option<(&str, &str)> {
+    let text = text.trim_start();
+    let body = text.strip_prefix('/')?;
+
+    if body.starts_with('/') {
+        return None;
+    }
+
+    let (name, rest) = body.split_once(char::is_whitespace).unwrap_or((body, ""));
+    if name.is_empty() {
+        return None;
+    }
+
+    Some((name, rest.trim()))
+}
+
+impl Invocation {
+    pub fn new(room_id: &str, user_id: &str, rest: &str) -> Self {
+        Invocation {
+            room_id: room_id.to_owned(),
+            user_id: user_id.to_owned(),
+            rest: rest.to_owned(),
+            args: rest.split_whitespace().map(str::to_owned).collect(),
+        }
+    }
+}
+
+fn nick(session: &mut WsChatSession, inv: Invocation, ctx: &mut ws::WebsocketContext<WsChatSession>) {
+    let Some(nickname) = inv.args.first().cloned() else {
+        return session.send_error(&inv.room_id, "Usage: /nick <nickname>", ctx);
+    };
+
+    if inv.args.len() > 1 || nickname.chars().count() > MAX_NICKNAME {
+        return session.send_error(&inv.room_id, &format!("Nicknames are a single word of at most {MAX_NICKNAME} characters"), ctx);
+    }
+
+    let db = session.db.clone();
+    let user_id = inv.user_id.clone();
+    let update = async move { db.set_nickname(&user_id, &nickname).await };
+
+    ctx.wait(update.into_actor(session).map(move |res, act, ctx| {
+        let user = match res {
+            Ok(Some(user)) => user,
+            Ok(None) => return act.send_error(&inv.room_id, "Create an account before choosing a nickname", ctx),
+            Err(err) => return act.send_error(&inv.room_id, &err.to_string(), ctx),
+        };
+
+        let lines = [format!("{} is now known as {}", user.id, user.nickname)];
+        if let Some(room) = act.room.clone() {
+            act.addr.do_send(server::RoomEvent {
+                room,
+                msg: command_reply(&inv.room_id, "nick", &lines),
+                skip_id: act.id,
+            });
+        }
+        act.reply(&inv.room_id, "nick", &lines, ctx);
+    }));
+}
+
+fn join(session: &mut WsChatSession, inv: Invocation, ctx: &mut ws::WebsocketContext<WsChatSession>) {
+    match inv.args.first().cloned() {
+        Some(room) if room == server::LOBBY => switch_room(session, room, ctx),
+        // Other rooms have to be created through the API first, so typos do not open new rooms
+        Some(room) => {
+            let db = session.db.clone();
+            let lookup = async move { db.find_room(&room).await.map(|found| (room, found.is_some())) };
+
+            ctx.wait(lookup.into_actor(session).map(move |res, act, ctx| match res {
+                Ok((room, true)) => switch_room(act, room, ctx),
+                Ok((room, false)) => act.send_error(&inv.room_id, &format!("No room found with id: {room}"), ctx),
+                Err(_) => act.send_error(&inv.room_id, "The room could not be looked up, try again in a moment", ctx),
+            }));
+        }
+        None => {
+            ctx.wait(session.addr.send(server::ListRooms).into_actor(session).map(move |res, act, ctx| {
+                let mut rooms = res.unwrap_or_default();
+                rooms.sort();
+                act.reply(&inv.room_id, "join", &rooms, ctx);
+            }));
+        }
+    }
+}
+
+fn leave(session: &mut WsChatSession, inv: Invocation, ctx: &mut ws::WebsocketContext<WsChatSession>) {
+    if session.room.as_deref() == Some(server::LOBBY) {
+        return session.send_error(&inv.room_id, "You are already in the lobby", ctx);
+    }
+
+    switch_room(session, server::LOBBY.to_owned(), ctx);
+}
+
+/// Moves the session into `room`, holding later frames back so they are sent to the new room
+fn switch_room(session: &mut WsChatSession, room: String, ctx: &mut ws::WebsocketContext<WsChatSession>) {
+    let join = server::Join { id: session.id, name: room.clone() };
+
+    ctx.wait(session.addr.send(join).into_actor(session).map(move |res, act, ctx| {
+        match res {
+            // The new room arrives as a `server::Notice`, which is handled before any later frame
+            Ok(Ok(())) => act.reply(&room, "join", &[format!("You joined {room}")], ctx),
+            Ok(Err(err)) => act.send_error(&room, &err.to_string(), ctx),
+            Err(_) => act.send_error(&room, "The chat server is unavailable", ctx),
+        }
+    }));
+}
+
+fn who(session: &mut WsChatSession, inv: Invocation, ctx: &mut ws::WebsocketContext<WsChatSession>) {
+    let Some(room) = session.room.clone() else {
+        return session.send_error(&inv.room_id, session::NOT_IN_A_ROOM, ctx);
+    };
+
+    let who = server::Who { room };
+
+    ctx.wait(session.addr.send(who).into_actor(session).map(move |res, act, ctx| {
+        act.reply(&inv.room_id, "who", &res.unwrap_or_default(), ctx);
+    }));
+}
+
+fn me(session: &mut WsChatSession, inv: Invocation, ctx: &mut ws::WebsocketContext<WsChatSession>) {
+    if inv.rest.is_empty() {
+        return session.send_error(&inv.room_id, "Usage: /me <action>", ctx);
+    }
+
+    let db = session.db.clone();
+    let user_id = inv.user_id.clone();
+    let lookup = async move { db.find_user(&user_id).await };
+
+    ctx.wait(lookup.into_actor(session).map(move |res, act, ctx| {
+        // Falls back to the user id if the user cannot be looked up, the action is still worth sending
+        let nickname = match res {
+            Ok(Some(user)) => user.nickname,
+            _ => inv.user_id.clone(),
+        };
+
+        let chat_msg = ChatMessage {
+            chat_type: ChatType::TEXT,
+            value: vec![format!("* {nickname} {}", inv.rest)],
+            room_id: inv.room_id,
+            user_id: inv.user_id,
+            id: act.id,
+        };
+
+        act.send_text(chat_msg, ctx);
+    }));
+}
+
+fn topic(session: &mut WsChatSession, inv: Invocation, ctx: &mut ws::WebsocketContext<WsChatSession>) {
+    let Some(room_id) = session.room.clone() else {
+        return session.send_error(&inv.room_id, session::NOT_IN_A_ROOM, ctx);
+    };
+
+    let db = session.db.clone();
+    let room = room_id.clone();
+    let actor = inv.user_id.clone();
+    let new_topic = (!inv.rest.is_empty()).then(|| inv.rest.clone());
+    let setting = new_topic.is_some();
+
+    let action = async move {
+        let Some(topic) = new_topic else {
+            let room = db.find_room(&room_id).await?;
+            return Ok(room.and_then(|room| room.topic));
+        };
+
+        moderation::authorize(&db, &room_id, &actor, models::Role::Moderator).await?;
+        let room = db.update_room(&room_id, models::RoomEdit { topic: Some(topic) }).await?;
+        Ok::<_, moderation::ModerationError>(room.and_then(|room| room.topic))
+    };
+
+    ctx.wait(action.into_actor(session).map(move |res, act, ctx| {
+        let topic = match res {
+            Ok(topic) => topic,
+            Err(err) => return act.send_error(&inv.room_id, &err.to_string(), ctx),
+        };
+
+        let lines = [match topic {
+            Some(topic) if setting => format!("{} set the topic to: {topic}", inv.user_id),
+            Some(topic) => format!("Topic: {topic}"),
+            None => "No topic is set".to_owned(),
+        }];
+
+        if setting {
+            act.addr.do_send(server::RoomEvent {
+                room,
+                msg: command_reply(&inv.room_id, "topic", &lines),
+                skip_id: act.id,
+            });
+        }
+        act.reply(&inv.room_id, "topic", &lines, ctx);
+    }));
+}
+
+fn help(session: &mut WsChatSession, inv: Invocation, ctx: &mut ws::WebsocketContext<WsChatSession>) {
+    let lines: Vec<String> = session
+        .commands
+        .commands()
+        .iter()
+        .map(|command| format!("{} - {}", command.usage, command.summary))
+        .collect();
+
+    session.reply(&inv.room_id, "help", &lines, ctx);
+}
+
+/// Serializes the outcome of a command, `value` holds the command name followed by its output
+pub fn command_reply(room_id: &str, name: &str, lines: &[String]) -> String {
+    let mut value = Vec::with_capacity(lines.len() + 1);
+    value.push(name.to_owned());
+    value.extend_from_slice(lines);
+
+    json!({
+        "room_id": room_id,
+        "value": value,
+        "chat_type": ChatType::COMMAND
+    })
+    .to_string()
+}
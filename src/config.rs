@@ -0,0 +1,763 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use mongodb::options::ResolverConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::ratelimit::Rate;
+
+/// Environment variable naming the configuration file
+const CONFIG_FILE_KEY: &str = "CONFIG_FILE";
+/// Read when `CONFIG_FILE` is not set, and skipped if it does not exist
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+/// Kept for compatibility with existing deployments, overrides `database.uri`
+const MONGODB_URI_KEY: &str = "MONGODB_URI";
+
+/// Settings read at startup from a TOML file, then overridden by `CHAT_<SECTION>_<KEY>` environment variables
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub tls: TlsConfig,
+    pub cors: CorsConfig,
+    pub database: DatabaseConfig,
+    pub session: SessionConfig,
+    pub flood: FloodConfig,
+    pub outbound: OutboundConfig,
+    pub connections: ConnectionsConfig,
+    pub rate_limit: HttpLimitConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub workers: usize,
+    /// Directory the web client is served from
+    pub static_dir: PathBuf,
+    /// How long shutdown waits for sessions to close and messages to be written
+    pub shutdown_timeout_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: "127.0.0.1".to_owned(),
+            port: 8080,
+            workers: 2,
+            static_dir: PathBuf::from("./static"),
+            shutdown_timeout_secs: 10,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+}
+
+/// HTTPS termination, enabled by setting both `cert_path` and `key_path`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file holding the certificate chain, leaf first
+    pub cert_path: Option<PathBuf>,
+    /// PEM file holding the PKCS#8, PKCS#1 or SEC1 private key
+    pub key_path: Option<PathBuf>,
+    /// How often the files are checked for a renewed certificate
+    pub reload_interval_secs: u64,
+    /// Plain HTTP port that redirects every request to HTTPS
+    pub redirect_port: Option<u16>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            cert_path: None,
+            key_path: None,
+            reload_interval_secs: 60,
+            redirect_port: None,
+        }
+    }
+}
+
+impl TlsConfig {
+    /// The certificate and key paths, if TLS is enabled
+    pub fn paths(&self) -> Option<(&Path, &Path)> {
+        Some((self.cert_path.as_deref()?, self.key_path.as_deref()?))
+    }
+
+    pub fn reload_interval(&self) -> Duration {
+        Duration::from_secs(self.reload_interval_secs)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins allowed to make cross-origin requests and to open WebSocket connections
+    pub allowed_origins: Vec<String>,
+    pub max_age_secs: usize,
+    /// Whether WebSocket handshakes without an `Origin` header are accepted. Browsers always send
+    /// one, so these come from other clients and cannot be used to hijack a user's session.
+    pub allow_missing_origin: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: vec!["http://localhost:3000".to_owned(), "http://localhost:8080".to_owned()],
+            max_age_secs: 3600,
+            allow_missing_origin: true,
+        }
+    }
+}
+
+impl CorsConfig {
+    /// Whether `origin` is in the allow-list, ignoring case and a trailing slash
+    pub fn allows(&self, origin: &str) -> bool {
+        let origin = origin.trim_end_matches('/');
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// Connection string, usually left out of the file and given through `MONGODB_URI`
+    pub uri: Option<String>,
+    pub name: String,
+    pub resolver: Resolver,
+    /// How long a query waits to reach the store before failing
+    pub timeout_secs: u64,
+    /// Times the connection string is resolved at startup before giving up
+    pub connect_attempts: u32,
+    /// How often the store is pinged while it is reachable
+    pub health_check_interval_secs: u64,
+    /// Longest wait between pings while the store is unreachable
+    pub max_backoff_secs: u64,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            uri: None,
+            name: "chatroomdb".to_owned(),
+            resolver: Resolver::Cloudflare,
+            timeout_secs: 5,
+            connect_attempts: 5,
+            health_check_interval_secs: 5,
+            max_backoff_secs: 30,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    pub fn health_check_interval(&self) -> Duration {
+        Duration::from_secs(self.health_check_interval_secs)
+    }
+
+    pub fn max_backoff(&self) -> Duration {
+        Duration::from_secs(self.max_backoff_secs)
+    }
+}
+
+/// DNS resolver used to look up `mongodb+srv` records
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolver {
+    /// The resolver configured on the host
+    System,
+    Cloudflare,
+    Google,
+    Quad9,
+}
+
+impl Resolver {
+    /// Returns the resolver to hand to the driver, `None` meaning the system one
+    pub fn config(self) -> Option<ResolverConfig> {
+        match self {
+            Resolver::System => None,
+            Resolver::Cloudflare => Some(ResolverConfig::cloudflare()),
+            Resolver::Google => Some(ResolverConfig::google()),
+            Resolver::Quad9 => Some(ResolverConfig::quad9()),
+        }
+    }
+}
+
+impl FromStr for Resolver {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "system" => Ok(Resolver::System),
+            "cloudflare" => Ok(Resolver::Cloudflare),
+            "google" => Ok(Resolver::Google),
+            "quad9" => Ok(Resolver::Quad9),
+            _ => Err("expected one of system, cloudflare, google or quad9".to_owned()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// How often sessions ping their client
+    pub heartbeat_interval_secs: u64,
+    /// How long a client may go without answering before its session is dropped
+    pub client_timeout_secs: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            heartbeat_interval_secs: 5,
+            client_timeout_secs: 10,
+        }
+    }
+}
+
+impl SessionConfig {
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval_secs)
+    }
+
+    pub fn client_timeout(&self) -> Duration {
+        Duration::from_secs(self.client_timeout_secs)
+    }
+}
+
+/// Limits applied to the frames a chat socket sends
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct FloodConfig {
+    pub session_text: Rate,
+    pub session_typing: Rate,
+    /// Shared by every session of the same user
+    pub user_text: Rate,
+    pub user_typing: Rate,
+    /// Identical text messages sent within this window are rejected
+    pub duplicate_window_secs: u64,
+    /// Violations that earn a mute
+    pub strikes_before_mute: u32,
+    pub mute_duration_secs: u64,
+    /// Mutes after which the session is disconnected
+    pub mutes_before_disconnect: u32,
+}
+
+impl Default for FloodConfig {
+    fn default() -> Self {
+        FloodConfig {
+            session_text: Rate::new(5, 1.0),
+            session_typing: Rate::new(10, 4.0),
+            user_text: Rate::new(10, 2.0),
+            user_typing: Rate::new(20, 8.0),
+            duplicate_window_secs: 10,
+            strikes_before_mute: 3,
+            mute_duration_secs: 30,
+            mutes_before_disconnect: 2,
+        }
+    }
+}
+
+impl FloodConfig {
+    pub fn duplicate_window(&self) -> Duration {
+        Duration::from_secs(self.duplicate_window_secs)
+    }
+
+    pub fn mute_duration(&self) -> Duration {
+        Duration::from_secs(self.mute_duration_secs)
+    }
+}
+
+/// How a session's outbound queue behaves once it is full
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboundConfig {
+    /// Most frames a session may have waiting to be written
+    pub capacity: usize,
+    /// Make room by dropping the oldest queued typing event before anything else
+    pub drop_typing_first: bool,
+    /// Close the session when there is nothing left to drop, otherwise the new frame is dropped
+    pub disconnect_when_full: bool,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        OutboundConfig {
+            capacity: 256,
+            drop_typing_first: true,
+            disconnect_when_full: true,
+        }
+    }
+}
+
+/// Caps on simultaneous WebSocket connections
+#[derive(Deserialize, Debug, Clone, Copy, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionsConfig {
+    pub max_total: usize,
+    /// Per remote address, as seen on the socket
+    pub max_per_ip: usize,
+    /// Per user, for connections opened with an access token
+    pub max_per_user: usize,
+}
+
+impl Default for ConnectionsConfig {
+    fn default() -> Self {
+        ConnectionsConfig {
+            max_total: 10_000,
+            max_per_ip: 50,
+            max_per_user: 10,
+        }
+    }
+}
+
+/// Per client limits for one route
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct RouteLimit {
+    /// Budget for each remote address
+    pub per_ip: Rate,
+    /// Budget for each user sending an `Authorization` header, applied on top of `per_ip`
+    pub per_user: Rate,
+}
+
+/// Limits for the HTTP API, keyed by route pattern such as `/users/{username}`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpLimitConfig {
+    /// Used by routes without their own entry in `routes`
+    pub default: RouteLimit,
+    /// Merged over the built in routes, so configuring one route keeps the limits of the others
+    #[serde(deserialize_with = "merge_default_routes")]
+    pub routes: HashMap<String, RouteLimit>,
+}
+
+impl Default for HttpLimitConfig {
+    fn default() -> Self {
+        HttpLimitConfig {
+            default: RouteLimit {
+                per_ip: Rate::new(60, 20.0),
+                per_user: Rate::new(30, 10.0),
+            },
+            routes: default_routes(),
+        }
+    }
+}
+
+/// Routes strict enough to need their own limits out of the box
+fn default_routes() -> HashMap<String, RouteLimit> {
+    let mut routes = HashMap::new();
+    routes.insert("/users/create".to_owned(), RouteLimit {
+        per_ip: Rate::new(5, 5.0 / 60.0),
+        per_user: Rate::new(3, 3.0 / 60.0),
+    });
+
+    routes
+}
+
+fn merge_default_routes<'de, D>(deserializer: D) -> Result<HashMap<String, RouteLimit>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let mut routes = default_routes();
+    routes.extend(HashMap::<String, RouteLimit>::deserialize(deserializer)?);
+
+    Ok(routes)
+}
+
+/// Why the configuration could not be loaded
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Env { key: String, value: String, reason: String },
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "Failed to read config file {}: {err}", path.display()),
+            ConfigError::Parse(path, err) => write!(f, "Invalid config file {}: {err}", path.display()),
+            ConfigError::Env { key, value, reason } => write!(f, "Invalid value {value:?} for {key}: {reason}"),
+            ConfigError::Invalid(msg) => write!(f, "Invalid configuration: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads the file named by `CONFIG_FILE`, or `config.toml` if present, applies environment overrides and validates the result
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match env::var(CONFIG_FILE_KEY) {
+            Ok(path) => Config::from_file(Path::new(&path))?,
+            Err(_) if Path::new(DEFAULT_CONFIG_FILE).exists() => Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            Err(_) => Config::default(),
+        };
+
+        config.apply_env()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|err| ConfigError::Read(path.to_owned(), err))?;
+        toml::from_str(&text).map_err(|err| ConfigError::Parse(path.to_owned(), err))
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        override_from_env("CHAT_SERVER_HOST", &mut self.server.host)?;
+        override_from_env("CHAT_SERVER_PORT", &mut self.server.port)?;
+        override_from_env("CHAT_SERVER_WORKERS", &mut self.server.workers)?;
+        override_from_env("CHAT_SERVER_STATIC_DIR", &mut self.server.static_dir)?;
+        override_from_env("CHAT_SERVER_SHUTDOWN_TIMEOUT_SECS", &mut self.server.shutdown_timeout_secs)?;
+        optional_from_env("CHAT_TLS_CERT_PATH", &mut self.tls.cert_path)?;
+        optional_from_env("CHAT_TLS_KEY_PATH", &mut self.tls.key_path)?;
+        override_from_env("CHAT_TLS_RELOAD_INTERVAL_SECS", &mut self.tls.reload_interval_secs)?;
+        optional_from_env("CHAT_TLS_REDIRECT_PORT", &mut self.tls.redirect_port)?;
+        override_from_env("CHAT_CORS_MAX_AGE_SECS", &mut self.cors.max_age_secs)?;
+        override_from_env("CHAT_CORS_ALLOW_MISSING_ORIGIN", &mut self.cors.allow_missing_origin)?;
+        override_from_env("CHAT_DATABASE_NAME", &mut self.database.name)?;
+        override_from_env("CHAT_DATABASE_RESOLVER", &mut self.database.resolver)?;
+        override_from_env("CHAT_DATABASE_TIMEOUT_SECS", &mut self.database.timeout_secs)?;
+        override_from_env("CHAT_DATABASE_CONNECT_ATTEMPTS", &mut self.database.connect_attempts)?;
+        override_from_env("CHAT_DATABASE_HEALTH_CHECK_INTERVAL_SECS", &mut self.database.health_check_interval_secs)?;
+        override_from_env("CHAT_DATABASE_MAX_BACKOFF_SECS", &mut self.database.max_backoff_secs)?;
+        override_from_env("CHAT_SESSION_HEARTBEAT_INTERVAL_SECS", &mut self.session.heartbeat_interval_secs)?;
+        override_from_env("CHAT_SESSION_CLIENT_TIMEOUT_SECS", &mut self.session.client_timeout_secs)?;
+        override_from_env("CHAT_FLOOD_DUPLICATE_WINDOW_SECS", &mut self.flood.duplicate_window_secs)?;
+        override_from_env("CHAT_FLOOD_STRIKES_BEFORE_MUTE", &mut self.flood.strikes_before_mute)?;
+        override_from_env("CHAT_FLOOD_MUTE_DURATION_SECS", &mut self.flood.mute_duration_secs)?;
+        override_from_env("CHAT_FLOOD_MUTES_BEFORE_DISCONNECT", &mut self.flood.mutes_before_disconnect)?;
+        override_from_env("CHAT_OUTBOUND_CAPACITY", &mut self.outbound.capacity)?;
+        override_from_env("CHAT_OUTBOUND_DROP_TYPING_FIRST", &mut self.outbound.drop_typing_first)?;
+        override_from_env("CHAT_OUTBOUND_DISCONNECT_WHEN_FULL", &mut self.outbound.disconnect_when_full)?;
+        override_from_env("CHAT_CONNECTIONS_MAX_TOTAL", &mut self.connections.max_total)?;
+        override_from_env("CHAT_CONNECTIONS_MAX_PER_IP", &mut self.connections.max_per_ip)?;
+        override_from_env("CHAT_CONNECTIONS_MAX_PER_USER", &mut self.connections.max_per_user)?;
+
+        if let Ok(origins) = env::var("CHAT_CORS_ALLOWED_ORIGINS") {
+            self.cors.allowed_origins = origins
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(str::to_owned)
+                .collect();
+        }
+
+        if let Ok(uri) = env::var(MONGODB_URI_KEY) {
+            self.database.uri = Some(uri);
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.server.host.trim().is_empty() {
+            return Err(ConfigError::Invalid("server.host must not be empty".to_owned()));
+        }
+
+        if self.server.workers == 0 {
+            return Err(ConfigError::Invalid("server.workers must be at least 1".to_owned()));
+        }
+
+        if self.tls.cert_path.is_some() != self.tls.key_path.is_some() {
+            return Err(ConfigError::Invalid("tls.cert_path and tls.key_path must be set together".to_owned()));
+        }
+
+        if self.tls.paths().is_some() && self.tls.reload_interval_secs == 0 {
+            return Err(ConfigError::Invalid("tls.reload_interval_secs must be at least 1".to_owned()));
+        }
+
+        match self.tls.redirect_port {
+            Some(_) if self.tls.paths().is_none() => {
+                return Err(ConfigError::Invalid("tls.redirect_port needs tls.cert_path and tls.key_path".to_owned()));
+            }
+            Some(port) if port == self.server.port => {
+                return Err(ConfigError::Invalid("tls.redirect_port must differ from server.port".to_owned()));
+            }
+            _ => {}
+        }
+
+        if let Some(origin) = self.cors.allowed_origins.iter().find(|origin| !is_origin(origin)) {
+            return Err(ConfigError::Invalid(format!("cors.allowed_origins entry {origin:?} must look like http://host[:port]")));
+        }
+
+        if self.database.uri.as_deref().is_none_or(|uri| uri.trim().is_empty()) {
+            return Err(ConfigError::Invalid(format!("database.uri must be set, or given through {MONGODB_URI_KEY}")));
+        }
+
+        if self.database.name.trim().is_empty() {
+            return Err(ConfigError::Invalid("database.name must not be empty".to_owned()));
+        }
+
+        let database_minimums = [
+            ("database.timeout_secs", self.database.timeout_secs),
+            ("database.connect_attempts", u64::from(self.database.connect_attempts)),
+            ("database.health_check_interval_secs", self.database.health_check_interval_secs),
+            ("database.max_backoff_secs", self.database.max_backoff_secs),
+        ];
+        if let Some((key, _)) = database_minimums.iter().find(|(_, value)| *value == 0) {
+            return Err(ConfigError::Invalid(format!("{key} must be at least 1")));
+        }
+
+        if self.session.heartbeat_interval_secs == 0 {
+            return Err(ConfigError::Invalid("session.heartbeat_interval_secs must be at least 1".to_owned()));
+        }
+
+        if self.session.client_timeout_secs <= self.session.heartbeat_interval_secs {
+            return Err(ConfigError::Invalid("session.client_timeout_secs must be longer than session.heartbeat_interval_secs".to_owned()));
+        }
+
+        let flood_rates = [
+            ("flood.session_text", self.flood.session_text),
+            ("flood.session_typing", self.flood.session_typing),
+            ("flood.user_text", self.flood.user_text),
+            ("flood.user_typing", self.flood.user_typing),
+        ];
+        if let Some((key, _)) = flood_rates.iter().find(|(_, rate)| !rate.is_valid()) {
+            return Err(ConfigError::Invalid(format!("{key} needs a burst of at least 1 and a positive per_second")));
+        }
+
+        if self.flood.strikes_before_mute == 0 {
+            return Err(ConfigError::Invalid("flood.strikes_before_mute must be at least 1".to_owned()));
+        }
+
+        if self.outbound.capacity == 0 {
+            return Err(ConfigError::Invalid("outbound.capacity must be at least 1".to_owned()));
+        }
+
+        let connection_minimums = [
+            ("connections.max_total", self.connections.max_total),
+            ("connections.max_per_ip", self.connections.max_per_ip),
+            ("connections.max_per_user", self.connections.max_per_user),
+        ];
+        if let Some((key, _)) = connection_minimums.iter().find(|(_, value)| *value == 0) {
+            return Err(ConfigError::Invalid(format!("{key} must be at least 1")));
+        }
+
+        let route_limits = std::iter::once(("default", &self.rate_limit.default))
+            .chain(self.rate_limit.routes.iter().map(|(route, limit)| (route.as_str(), limit)));
+        for (route, limit) in route_limits {
+            if !limit.per_ip.is_valid() || !limit.per_user.is_valid() {
+                return Err(ConfigError::Invalid(format!("rate_limit {route:?} needs a burst of at least 1 and a positive per_second")));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn override_from_env<T>(key: &str, target: &mut T) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let Ok(value) = env::var(key) else {
+        return Ok(());
+    };
+
+    *target = value.parse().map_err(|err: T::Err| ConfigError::Env {
+        key: key.to_owned(),
+        reason: err.to_string(),
+        value,
+    })?;
+
+    Ok(())
+}
+
+/// Like `override_from_env`, for settings that are unset by default
+fn optional_from_env<T>(key: &str, target: &mut Option<T>) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let Ok(value) = env::var(key) else {
+        return Ok(());
+    };
+
+    *target = Some(value.parse().map_err(|err: T::Err| ConfigError::Env {
+        key: key.to_owned(),
+        reason: err.to_string(),
+        value,
+    })?);
+
+    Ok(())
+}
+
+/// Whether a CORS origin is a scheme and host with no path, as browsers send it
+fn is_origin(origin: &str) -> bool {
+    let Some(rest) = origin.strip_prefix("http://").or_else(|| origin.strip_prefix("https://")) else {
+        return false;
+    };
+
+    !rest.is_empty() && !rest.contains('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Environment variables are shared by the whole test binary, so tests setting them take turns
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// The defaults plus the one setting without a default
+    fn valid() -> Config {
+        let mut config = Config::default();
+        config.database.uri = Some("mongodb://localhost:27017".to_owned());
+        config
+    }
+
+    fn invalid(config: &Config) -> String {
+        match config.validate() {
+            Err(ConfigError::Invalid(msg)) => msg,
+            other => panic!("expected the configuration to be invalid, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn environment_overrides_file_values() {
+        let _env = ENV.lock().unwrap();
+        env::set_var("CHAT_SERVER_PORT", "9000");
+        env::set_var("CHAT_TLS_REDIRECT_PORT", "9080");
+        env::set_var("CHAT_CORS_ALLOWED_ORIGINS", "https://a.example, ,https://b.example");
+        env::set_var(MONGODB_URI_KEY, "mongodb://db.example:27017");
+
+        let mut config: Config = toml::from_str("[server]\nport = 8000\n").unwrap();
+        let res = config.apply_env();
+
+        for key in ["CHAT_SERVER_PORT", "CHAT_TLS_REDIRECT_PORT", "CHAT_CORS_ALLOWED_ORIGINS", MONGODB_URI_KEY] {
+            env::remove_var(key);
+        }
+        res.unwrap();
+
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.tls.redirect_port, Some(9080));
+        assert_eq!(config.cors.allowed_origins, ["https://a.example", "https://b.example"]);
+        assert_eq!(config.database.uri.as_deref(), Some("mongodb://db.example:27017"));
+    }
+
+    #[test]
+    fn unparsable_environment_values_are_rejected() {
+        let _env = ENV.lock().unwrap();
+        env::set_var("CHAT_SERVER_WORKERS", "many");
+
+        let res = valid().apply_env();
+        env::remove_var("CHAT_SERVER_WORKERS");
+
+        match res {
+            Err(ConfigError::Env { key, value, .. }) => assert_eq!((key.as_str(), value.as_str()), ("CHAT_SERVER_WORKERS", "many")),
+            other => panic!("expected an environment error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid_once_the_database_is_set() {
+        valid().validate().unwrap();
+        assert!(invalid(&Config::default()).starts_with("database.uri"));
+    }
+
+    #[test]
+    fn zero_rates_are_rejected() {
+        let mut config = valid();
+        config.flood.session_text = Rate::new(0, 1.0);
+        assert!(invalid(&config).starts_with("flood.session_text"));
+
+        let mut config = valid();
+        config.rate_limit.default.per_user = Rate::new(10, 0.0);
+        assert!(invalid(&config).contains("\"default\""));
+
+        let mut config = valid();
+        config.rate_limit.routes.get_mut("/users/create").unwrap().per_ip = Rate::new(0, 1.0);
+        assert!(invalid(&config).contains("/users/create"));
+    }
+
+    #[test]
+    fn bad_addresses_are_rejected() {
+        let mut config = valid();
+        config.server.host = " ".to_owned();
+        assert!(invalid(&config).starts_with("server.host"));
+
+        for origin in ["localhost:3000", "ftp://example.com", "https://example.com/chat", "https://"] {
+            let mut config = valid();
+            config.cors.allowed_origins = vec![origin.to_owned()];
+            assert!(invalid(&config).starts_with("cors.allowed_origins"), "{origin} was accepted");
+        }
+
+        let mut config = valid();
+        config.database.uri = Some("  ".to_owned());
+        assert!(invalid(&config).starts_with("database.uri"));
+    }
+
+    #[test]
+    fn tls_needs_both_paths() {
+        let mut config = valid();
+        config.tls.cert_path = Some(PathBuf::from("cert.pem"));
+        assert!(invalid(&config).starts_with("tls.cert_path and tls.key_path"));
+
+        config.tls.key_path = Some(PathBuf::from("key.pem"));
+        config.validate().unwrap();
+
+        config.tls.reload_interval_secs = 0;
+        assert!(invalid(&config).starts_with("tls.reload_interval_secs"));
+
+        let mut config = valid();
+        config.tls.redirect_port = Some(8081);
+        assert!(invalid(&config).starts_with("tls.redirect_port"));
+    }
+
+    #[test]
+    fn routes_missing_from_the_file_keep_their_defaults() {
+        let config: Config = toml::from_str("[rate_limit]\ndefault = { per_ip = { burst = 1, per_second = 1.0 }, per_user = { burst = 1, per_second = 1.0 } }\n").unwrap();
+
+        assert_eq!(config.rate_limit.default.per_ip.burst, 1);
+        assert_eq!(config.rate_limit.routes.len(), HttpLimitConfig::default().routes.len());
+        assert_eq!(config.rate_limit.routes["/users/create"].per_ip.burst, 5);
+    }
+
+    #[test]
+    fn configured_routes_are_merged_over_the_built_in_ones() {
+        let config: Config = toml::from_str(
+            r#"
+            [rate_limit.routes."/rooms"]
+            per_ip = { burst = 10, per_second = 1.0 }
+            per_user = { burst = 5, per_second = 0.5 }
+            "#,
+        )
+        .unwrap();
+
+        let routes = &config.rate_limit.routes;
+        assert_eq!(routes["/rooms"].per_ip.burst, 10);
+        assert_eq!(routes["/users/create"].per_ip.burst, 5);
+
+        let config: Config = toml::from_str(
+            r#"
+            [rate_limit.routes."/users/create"]
+            per_ip = { burst = 2, per_second = 0.1 }
+            per_user = { burst = 1, per_second = 0.1 }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.rate_limit.routes.len(), 1);
+        assert_eq!(config.rate_limit.routes["/users/create"].per_ip.burst, 2);
+    }
+}
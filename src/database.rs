@@ -1,65 +1,211 @@
 use mongodb::Client;
 use mongodb::Collection;
 use mongodb::bson::doc;
-use mongodb::options::{ClientOptions, ResolverConfig};
+use mongodb::bson::oid::ObjectId;
+use mongodb::error::ErrorKind;
+use mongodb::options::{ClientOptions, FindOptions};
 use futures::TryStreamExt;
+use tracing::instrument;
 
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::time::SystemTime;
-use std::env;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::cache::{CacheStats, TtlCache};
+use crate::config::DatabaseConfig;
+use crate::metrics;
+use crate::models::{AccessToken, Announcement, NewRoom, RoomResponse, RoomEdit, RoomFilters, Conversation, Report, ReportQuery, ReportStatus, Role, Sanction, User, Room};
+pub type DbError = Box<dyn std::error::Error + Send + Sync>;
 
-use dotenv::dotenv;
+const CACHE_TTL: Duration = Duration::from_secs(30);
+/// How long the last value read from the store is kept to be served while the store is unreachable
+const LAST_KNOWN_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// First wait before retrying the store, doubled on every failure
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
 
-use crate::models::{RoomResponse, NewConversation, Conversation, User, Room};
-pub type DbError = Box<dyn std::error::Error + Send + Sync>;
+/// Returned without querying while the store is unreachable
+#[derive(Debug)]
+pub struct Unavailable;
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The database is unavailable")
+    }
+}
+
+impl std::error::Error for Unavailable {}
 
-const DB_NAME: &str = "chatroomdb";
+/// Whether a query failed because the store is unreachable rather than because of the request
+pub fn is_unavailable(err: &DbError) -> bool {
+    err.is::<Unavailable>()
+}
 
 /// A struct containing collections of Users, Conversations, and Rooms in our database
 #[derive(Debug, Clone)]
 pub struct Database {
+    client_db: mongodb::Database,
+    /// Set by `monitor`, queries are refused while it is false
+    available: Arc<AtomicBool>,
     users: Collection<User>,
     conversations: Collection<Conversation>,
     rooms: Collection<Room>, 
+    sanctions: Collection<Sanction>,
+    reports: Collection<Report>,
+    announcements: Collection<Announcement>,
+    tokens: Collection<AccessToken>,
+    /// Users and rooms by id, including ids that do not exist so unknown ones are not looked up on every message
+    user_cache: Arc<TtlCache<Option<User>>>,
+    room_cache: Arc<TtlCache<Option<Room>>>,
+    /// Rooms as last read from the store, served while it is unreachable so their filters stay in force
+    known_rooms: Arc<TtlCache<Option<Room>>>,
+    sanction_cache: Arc<TtlCache<Vec<Sanction>>>,
+    /// The user each token hash belongs to, if any
+    token_cache: Arc<TtlCache<Option<String>>>,
+    /// Tokens verified against the store, served while it is unreachable so their users can still connect
+    known_tokens: Arc<TtlCache<String>>,
 }
 
 impl Database {
-    /// Returns an instance of our Database with designated handles to each collection.
+    /// Connects to the store, returning an instance with designated handles to each collection.
+    /// 
+    /// The connection string is resolved up to `connect_attempts` times with backoff. If the store
+    /// itself cannot be reached the instance starts out unavailable, see `monitor`.
     /// 
     /// # Arguments
     /// 
-    /// * `key` - A string slice that holds the environment variable to conenct to the database
+    /// * `config` - The validated database section of the configuration
     /// 
-    /// # Panics
+    /// # Errors
     /// 
-    /// If there is an error connecting to the client
+    /// If the connection string is invalid, or could not be resolved in the given attempts
     /// 
     /// # Examples 
     /// 
+    /// ```ignore
+    /// let db = Database::new(&config.database).await?;
     /// ```
-    /// let db = Database::new("MONGODB_URI");
-    /// ```
-    pub async fn new(key: &str) -> Self {
-        dotenv().ok();
+    pub async fn new(config: &DatabaseConfig) -> Result<Self, DbError> {
+        let client_uri = config.uri.as_deref().ok_or("database.uri is not set")?;
+        let mut delay = INITIAL_BACKOFF;
+        let mut attempt = 1;
+
+        let mut options = loop {
+            let parsed = match config.resolver.config() {
+                Some(resolver) => ClientOptions::parse_with_resolver_config(client_uri, resolver).await,
+                None => ClientOptions::parse(client_uri).await,
+            };
+
+            match parsed {
+                Ok(options) => break options,
+                // SRV records can fail to resolve while the network comes up, anything else is a mistake in the URI
+                Err(err) if matches!(*err.kind, ErrorKind::DnsResolve { .. }) && attempt < config.connect_attempts => {
+                    tracing::warn!(error = %err, attempt, retry_in_ms = delay.as_millis() as u64, "Failed to resolve the database URI");
+                    actix_rt::time::sleep(delay).await;
+                    delay = (delay * 2).min(config.max_backoff());
+                    attempt += 1;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        };
 
-        let client_uri = env::var(key).expect("You must set the MONGODB_URI environment var!");
-        let options = ClientOptions::parse_with_resolver_config(&client_uri, ResolverConfig::cloudflare()).await.unwrap();
-        let client_conn = Client::with_options(options).unwrap();
-        
+        options.connect_timeout.get_or_insert(config.timeout());
+        options.server_selection_timeout.get_or_insert(config.timeout());
+
+        let client_conn = Client::with_options(options)?;
+        let db = Database::with_client_db(client_conn.database(&config.name));
+
+        match db.ping(config.timeout()).await {
+            Ok(_) => db.available.store(true, Ordering::Relaxed),
+            Err(err) => tracing::warn!(error = %err, "Database is unreachable, starting without persistence"),
+        }
+
+        Ok(db)
+    }
+
+    /// Handles to the collections of `client_db`, unavailable until the store answers a ping
+    fn with_client_db(client_db: mongodb::Database) -> Self {
         Database {
-            users: client_conn.database(DB_NAME).collection("users"),
-            conversations: client_conn.database(DB_NAME).collection("conversations"),
-            rooms: client_conn.database(DB_NAME).collection("rooms"),
+            users: client_db.collection("users"),
+            conversations: client_db.collection("conversations"),
+            rooms: client_db.collection("rooms"),
+            sanctions: client_db.collection("sanctions"),
+            reports: client_db.collection("reports"),
+            announcements: client_db.collection("announcements"),
+            tokens: client_db.collection("tokens"),
+            client_db,
+            available: Arc::new(AtomicBool::new(false)),
+            user_cache: Arc::new(TtlCache::new(CACHE_TTL)),
+            room_cache: Arc::new(TtlCache::new(CACHE_TTL)),
+            known_rooms: Arc::new(TtlCache::new(LAST_KNOWN_TTL)),
+            sanction_cache: Arc::new(TtlCache::new(CACHE_TTL)),
+            token_cache: Arc::new(TtlCache::new(CACHE_TTL)),
+            known_tokens: Arc::new(TtlCache::new(LAST_KNOWN_TTL)),
         }
     }
 
-    /// Finds a user from the database with the given username
+    /// An instance whose store is unreachable, for tests that should not need one running
+    #[cfg(test)]
+    pub(crate) async fn unavailable() -> Self {
+        let options = ClientOptions::parse("mongodb://127.0.0.1:1").await.unwrap();
+        Database::with_client_db(Client::with_options(options).unwrap().database("test"))
+    }
+
+    /// Whether the store answered the last health check
+    pub fn is_available(&self) -> bool {
+        self.available.load(Ordering::Relaxed)
+    }
+
+    /// Pings the store for as long as the process runs, backing off while it is unreachable.
+    /// 
+    /// Queries fail straight away with `Unavailable` while the store is down, so chat carries on
+    /// without persistence instead of every request waiting out the driver's timeout.
+    pub async fn monitor(self, config: DatabaseConfig) {
+        let mut delay = if self.is_available() { config.health_check_interval() } else { INITIAL_BACKOFF };
+
+        loop {
+            actix_rt::time::sleep(delay).await;
+
+            let res = self.ping(config.timeout()).await;
+            let was_available = self.available.swap(res.is_ok(), Ordering::Relaxed);
+
+            delay = match res {
+                Ok(_) => {
+                    if !was_available {
+                        tracing::info!("Database is reachable again, persistence resumed");
+                    }
+                    config.health_check_interval()
+                }
+                Err(err) if was_available => {
+                    tracing::error!(error = %err, "Database is unreachable, pausing persistence");
+                    INITIAL_BACKOFF
+                }
+                Err(err) => {
+                    let next = (delay * 2).min(config.max_backoff());
+                    tracing::debug!(error = %err, retry_in_ms = next.as_millis() as u64, "Database is still unreachable");
+                    next
+                }
+            };
+        }
+    }
+
+    /// Fails fast with `Unavailable` while the store is known to be down
+    fn check_available(&self) -> Result<(), DbError> {
+        if self.is_available() {
+            Ok(())
+        } else {
+            Err(Box::new(Unavailable))
+        }
+    }
+
+    /// Finds a user with the given username, serving it, or its absence, from the cache when possible
     /// 
     /// # Examples
     /// 
-    /// ```
-    /// let db = Database::new("MONGODB_URI");
+    /// ```ignore
+    /// let db = Database::new(&config.database).await?;
     /// let user_result = db.find_user("user1").await.unwrap();
     /// 
     /// match user_result {
@@ -67,19 +213,48 @@ impl Database {
     ///     None => println!("User could not be found");
     /// }
     /// ```
+    #[instrument(level = "debug", skip(self), err)]
     pub async fn find_user(&self, username: &str) -> Result<Option<User>, DbError> {
-        println!("{username}");
+        if let Some(user) = self.user_cache.get(username) {
+            return Ok(user);
+        }
+
+        self.check_available()?;
+
+        let _timer = metrics::db_timer("find_user");
         let filter = doc! {"_id": username};
         let query = self.users.find_one(filter, None).await?;
+
+        self.user_cache.insert(username.to_owned(), query.clone());
         
         Ok(query)
     }
 
-    /// Helper function to find a room from the database with the given id
-    async fn find_room(&self, room_id: &str) -> Result<Option<Room>, DbError> {
+    /// Finds a room with the given id, serving it, or its absence, from the cache when possible.
+    ///
+    /// While the store is unreachable the room is served as it was last read, if it was read in
+    /// the last `LAST_KNOWN_TTL`.
+    #[instrument(level = "debug", skip(self), err)]
+    pub async fn find_room(&self, room_id: &str) -> Result<Option<Room>, DbError> {
+        if let Some(room) = self.room_cache.get(room_id) {
+            return Ok(room);
+        }
+
+        if !self.is_available() {
+            if let Some(room) = self.known_rooms.get(room_id) {
+                return Ok(room);
+            }
+        }
+
+        self.check_available()?;
+
+        let _timer = metrics::db_timer("find_room");
         let filter = doc! {"_id": room_id};
         let query = self.rooms.find_one(filter, None).await?;
 
+        self.room_cache.insert(room_id.to_owned(), query.clone());
+        self.known_rooms.insert(room_id.to_owned(), query.clone());
+
         Ok(query)
     }
 
@@ -87,8 +262,8 @@ impl Database {
     /// 
     /// # Examples
     /// 
-    /// ```
-    /// let db = Database::new("MONGODB_URI");
+    /// ```ignore
+    /// let db = Database::new(&config.database).await?;
     /// let new_user_result = db.add_user("user2".to_owned(), "jimmy".to_owned()).await;
     /// 
     /// match new_user_result {
@@ -99,17 +274,19 @@ impl Database {
     ///     }
     /// }
     /// ```
+    #[instrument(level = "debug", skip(self), err)]
     pub async fn add_user(&self, username: String, nickname: String) -> Result<User, DbError> {
+        let _timer = metrics::db_timer("add_user");
+        self.check_available()?;
+
         let query_result = self.find_user(&username).await?;
 
-        match query_result {
-            Some(_user) => return Err(Box::new(std::io::Error::new(
+        if query_result.is_some() {
+            return Err(Box::new(std::io::Error::new(
                 std::io::ErrorKind::AlreadyExists,
                 "Duplicate found",
-            ))),
-
-            None => {},
-        };
+            )));
+        }
 
         let user = User {
             id: username,
@@ -118,74 +295,131 @@ impl Database {
         };
 
         let _insert_result = self.users.insert_one(user.clone(), None).await?;
+        self.user_cache.invalidate(&user.id);
 
         Ok(user)
     }
 
-    /// Creates and inserts a conversation into a database
+    /// Stores the hash of an access token issued to a user
     /// 
-    /// # Paramters
+    /// # Examples
     /// 
-    /// * `new` - A struct containing the message contents, the username of the user that sent it, and the id of the room the message was sent in
+    /// ```ignore
+    /// let db = Database::new(&config.database).await?;
+    /// let token = auth::new_token();
+    /// db.add_token(AccessToken {
+    ///     hash: auth::hash_token(&token),
+    ///     user_id: "user1".to_owned(),
+    ///     created_at: SystemTime::now().into(),
+    /// }).await?;
+    /// ```
+    #[instrument(level = "debug", skip(self, token), fields(user_id = %token.user_id), err)]
+    pub async fn add_token(&self, token: AccessToken) -> Result<(), DbError> {
+        let _timer = metrics::db_timer("add_token");
+        self.check_available()?;
+
+        self.tokens.insert_one(token.clone(), None).await?;
+        self.token_cache.invalidate(&token.hash);
+
+        Ok(())
+    }
+
+    /// Finds the user an access token was issued to from the token's hash, serving it from the cache when possible.
+    ///
+    /// While the store is unreachable tokens verified in the last `LAST_KNOWN_TTL` are still accepted.
+    /// Tokens are never revoked, so this cannot let a withdrawn token back in.
+    #[instrument(level = "debug", skip_all, err)]
+    pub async fn find_token_user(&self, hash: &str) -> Result<Option<String>, DbError> {
+        if let Some(user_id) = self.token_cache.get(hash) {
+            return Ok(user_id);
+        }
+
+        if !self.is_available() {
+            if let Some(user_id) = self.known_tokens.get(hash) {
+                return Ok(Some(user_id));
+            }
+        }
+
+        self.check_available()?;
+
+        let _timer = metrics::db_timer("find_token_user");
+        let query = self.tokens.find_one(doc! {"_id": hash}, None).await?;
+        let user_id = query.map(|token| token.user_id);
+
+        self.token_cache.insert(hash.to_owned(), user_id.clone());
+        if let Some(user_id) = &user_id {
+            self.known_tokens.insert(hash.to_owned(), user_id.clone());
+        }
+
+        Ok(user_id)
+    }
+
+    /// Changes the nickname of a user, returning the updated user if it exists
     /// 
     /// # Examples
     /// 
+    /// ```ignore
+    /// let db = Database::new(&config.database).await?;
+    /// let user = db.set_nickname("user1", "jimmy").await?;
     /// ```
-    /// let db = Database::new("MONGODB_URI");
-    /// let conversation_result = db.add_conversation(NewConversation {
+    #[instrument(level = "debug", skip(self), err)]
+    pub async fn set_nickname(&self, username: &str, nickname: &str) -> Result<Option<User>, DbError> {
+        let _timer = metrics::db_timer("set_nickname");
+        self.check_available()?;
+
+        let filter = doc! {"_id": username};
+        let update = doc! {"$set": {"nickname": nickname}};
+        self.users.update_one(filter, update, None).await?;
+        self.user_cache.invalidate(username);
+
+        self.find_user(username).await
+    }
+
+    /// Inserts a batch of already validated conversations into the database with a single write
+    /// 
+    /// # Paramters
+    /// 
+    /// * `conversations` - The conversations to insert, in the order they were sent
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// let db = Database::new(&config.database).await?;
+    /// let insert_result = db.insert_conversations(vec![Conversation {
+    ///     id: None,
     ///     message: "Hello World!".to_owned(),
     ///     user_id: "user1".to_owned(),
     ///     room_id: "main".to_owned(),
-    /// }).await;
+    ///     created_at: SystemTime::now().into(),
+    /// }]).await;
     /// 
-    /// match conversation_result {
-    ///     Ok(_convo) => println!("Conversation inserted successfully!");
-    ///     Err(error) => match error.kind() {
-    ///         ErrorKind::NotFound => println!("User or room does not exist!");
-    ///         _ => println!("Some other error happened!");
-    ///     }
+    /// match insert_result {
+    ///     Ok(()) => println!("Conversations inserted successfully!");
+    ///     Err(e) => println!("Some error happened {:?}", e);
     /// }
     /// ```
-    pub async fn add_conversation(&self, new: NewConversation) -> Result<Conversation, DbError> {
-        let try_user = self.find_user(new.user_id.as_str()).await?;
-        match try_user {
-            Some(_user) => {},
+    #[instrument(level = "debug", skip(self, conversations), fields(count = conversations.len()), err)]
+    pub async fn insert_conversations(&self, conversations: Vec<Conversation>) -> Result<(), DbError> {
+        let _timer = metrics::db_timer("insert_conversations");
+        self.check_available()?;
 
-            None => return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "User does not exist",
-            ))),
+        if conversations.is_empty() {
+            return Ok(());
         }
 
-        let try_room = self.find_room(new.room_id.as_str()).await?;
-        match try_room {
-            Some(_room) => {},
+        let count = conversations.len();
+        let _insert_result = self.conversations.insert_many(conversations, None).await?;
+        metrics::record_persisted(count);
 
-            None => return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "Room does not exist",
-            ))),
-        }
-
-        let message = Conversation {
-            id: None,
-            message: new.message,
-            user_id: new.user_id,
-            room_id: new.room_id,
-            created_at: SystemTime::now().into(),
-        };
-
-        let _insert_result = self.conversations.insert_one(message.clone(), None).await?;
-
-        Ok(message)
+        Ok(())
     }
 
     /// Retrives all conversations in a given room
     /// 
     /// # Examples
     /// 
-    /// ```
-    /// let db = Database::new("MONGODB_URI");
+    /// ```ignore
+    /// let db = Database::new(&config.database).await?;
     /// let conversations_result = db.get_conversations_by_room_id("main")
     /// 
     /// match conversations_result {
@@ -196,7 +430,11 @@ impl Database {
     ///     }
     /// }
     /// ```
+    #[instrument(level = "debug", skip(self), err)]
     pub async fn get_conversations_by_room_id(&self, room_id: &str) -> Result<Vec<Conversation>, DbError> {
+        let _timer = metrics::db_timer("get_conversations_by_room_id");
+        self.check_available()?;
+
         let try_room = self.find_room(room_id).await?;
         match try_room {
             Some(_room) => {},
@@ -219,8 +457,8 @@ impl Database {
     /// 
     /// # Examples
     /// 
-    /// ```
-    /// let db = Database::new("MONGODB_URI");
+    /// ```ignore
+    /// let db = Database::new(&config.database).await?;
     /// let rooms_result = db.get_all_rooms();
     /// 
     /// let rooms = match rooms_result {
@@ -228,7 +466,11 @@ impl Database {
     ///     Err(e) => panic!("Some error happened {:?}", e);
     /// }
     /// ```
+    #[instrument(level = "debug", skip(self), err)]
     pub async fn get_all_rooms(&self) -> Result<Vec<RoomResponse>, DbError> {
+        let _timer = metrics::db_timer("get_all_rooms");
+        self.check_available()?;
+
         let query = self.rooms.find(None, None).await?;
         let rooms_data: Vec<Room> = query.try_collect().await?;
 
@@ -238,7 +480,7 @@ impl Database {
 
         for room in &data {
             let user_ids = &room.participant_ids;
-            for id in user_ids.to_vec() {
+            for id in user_ids.iter().cloned() {
                 ids.insert(id);
             }
             rooms_map.insert(&room.id, user_ids.to_vec());
@@ -257,13 +499,378 @@ impl Database {
             let users = rooms_map
             .get(&room.id)
             .unwrap()
-            .into_iter()
+            .iter()
             .map(|id| users_map.get(id).unwrap().clone())
             .collect::<Vec<_>>();
     
-            return RoomResponse{ room, users };
+            RoomResponse{ room, users }
         }).collect::<Vec<_>>();
     
         Ok(response_rooms)
     }
-}
\ No newline at end of file
+
+    /// Creates a room owned by `owner`, who also becomes its first participant
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// let db = Database::new(&config.database).await?;
+    /// let room = db.add_room(NewRoom { id: "main".to_owned(), topic: None }, "user1").await?;
+    /// ```
+    #[instrument(level = "debug", skip(self), err)]
+    pub async fn add_room(&self, new: NewRoom, owner: &str) -> Result<Room, DbError> {
+        let _timer = metrics::db_timer("add_room");
+        self.check_available()?;
+
+        if self.find_room(&new.id).await?.is_some() {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "Duplicate found",
+            )));
+        }
+
+        let room = Room {
+            id: new.id,
+            last_message: String::new(),
+            participant_ids: vec![owner.to_owned()],
+            created_at: SystemTime::now().into(),
+            topic: new.topic,
+            roles: HashMap::from([(owner.to_owned(), Role::Owner)]),
+            filters: RoomFilters::default(),
+        };
+
+        self.rooms.insert_one(room.clone(), None).await?;
+        self.room_cache.invalidate(&room.id);
+
+        Ok(room)
+    }
+
+    /// Replaces the roles of a room with the given map
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// let db = Database::new(&config.database).await?;
+    /// let mut room = db.find_room("main").await?.unwrap();
+    /// room.roles.insert("user2".to_owned(), Role::Moderator);
+    /// db.set_roles("main", &room.roles).await?;
+    /// ```
+    #[instrument(level = "debug", skip(self, roles), err)]
+    pub async fn set_roles(&self, room_id: &str, roles: &HashMap<String, Role>) -> Result<(), DbError> {
+        let _timer = metrics::db_timer("set_roles");
+        self.check_available()?;
+
+        let filter = doc! {"_id": room_id};
+        let update = doc! {"$set": {"roles": bson::to_bson(roles)?}};
+        self.rooms.update_one(filter, update, None).await?;
+        self.room_cache.invalidate(room_id);
+
+        Ok(())
+    }
+
+    /// Replaces the message filter configuration of a room, returning the updated room if it exists
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// let db = Database::new(&config.database).await?;
+    /// let filters = RoomFilters { redact_pii: true, ..Default::default() };
+    /// let room = db.set_filters("main", &filters).await?;
+    /// ```
+    #[instrument(level = "debug", skip(self, filters), err)]
+    pub async fn set_filters(&self, room_id: &str, filters: &RoomFilters) -> Result<Option<Room>, DbError> {
+        let _timer = metrics::db_timer("set_filters");
+        self.check_available()?;
+
+        let filter = doc! {"_id": room_id};
+        let update = doc! {"$set": {"filters": bson::to_bson(filters)?}};
+        self.rooms.update_one(filter, update, None).await?;
+        self.room_cache.invalidate(room_id);
+
+        self.find_room(room_id).await
+    }
+
+    /// Applies an edit to a room, returning the updated room if it exists
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// let db = Database::new(&config.database).await?;
+    /// let room = db.update_room("main", RoomEdit { topic: Some("Rust".to_owned()) }).await?;
+    /// ```
+    #[instrument(level = "debug", skip(self), err)]
+    pub async fn update_room(&self, room_id: &str, edit: RoomEdit) -> Result<Option<Room>, DbError> {
+        let _timer = metrics::db_timer("update_room");
+        self.check_available()?;
+
+        let filter = doc! {"_id": room_id};
+        let update = match edit.topic {
+            Some(topic) => doc! {"$set": {"topic": topic}},
+            None => doc! {"$unset": {"topic": ""}},
+        };
+        self.rooms.update_one(filter, update, None).await?;
+        self.room_cache.invalidate(room_id);
+
+        self.find_room(room_id).await
+    }
+
+    /// Deletes a room and every conversation in it, returning whether the room existed
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// let db = Database::new(&config.database).await?;
+    /// if db.delete_room("main").await? {
+    ///     println!("Room deleted");
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip(self), err)]
+    pub async fn delete_room(&self, room_id: &str) -> Result<bool, DbError> {
+        let _timer = metrics::db_timer("delete_room");
+        self.check_available()?;
+
+        let result = self.rooms.delete_one(doc! {"_id": room_id}, None).await?;
+        self.room_cache.invalidate(room_id);
+        self.known_rooms.invalidate(room_id);
+
+        if result.deleted_count == 0 {
+            return Ok(false);
+        }
+
+        self.conversations.delete_many(doc! {"room_id": room_id}, None).await?;
+
+        Ok(true)
+    }
+
+    /// Stores a mute or ban
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// let db = Database::new(&config.database).await?;
+    /// db.add_sanction(Sanction {
+    ///     id: None,
+    ///     user_id: "user2".to_owned(),
+    ///     room_id: Some("main".to_owned()),
+    ///     kind: SanctionKind::Mute,
+    ///     issued_by: "user1".to_owned(),
+    ///     created_at: SystemTime::now().into(),
+    ///     expires_at: None,
+    /// }).await?;
+    /// ```
+    #[instrument(level = "debug", skip(self, sanction), fields(user_id = %sanction.user_id), err)]
+    pub async fn add_sanction(&self, sanction: Sanction) -> Result<Sanction, DbError> {
+        let _timer = metrics::db_timer("add_sanction");
+        self.check_available()?;
+
+        let result = self.sanctions.insert_one(sanction.clone(), None).await?;
+        self.sanction_cache.invalidate(&sanction.user_id);
+
+        Ok(Sanction { id: result.inserted_id.as_object_id(), ..sanction })
+    }
+
+    /// Retrieves every mute and ban on a user that has not expired yet, in any room
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// let db = Database::new(&config.database).await?;
+    /// let banned = db.active_sanctions("user2").await?
+    ///     .iter()
+    ///     .any(|sanction| sanction.kind == SanctionKind::Ban && sanction.applies_to("main"));
+    /// ```
+    #[instrument(level = "debug", skip(self), err)]
+    pub async fn active_sanctions(&self, user_id: &str) -> Result<Vec<Sanction>, DbError> {
+        if let Some(sanctions) = self.sanction_cache.get(user_id) {
+            return Ok(sanctions.into_iter().filter(Sanction::is_active).collect());
+        }
+
+        self.check_available()?;
+
+        let _timer = metrics::db_timer("active_sanctions");
+        let filter = doc! {
+            "user_id": user_id,
+            "$or": [{"expires_at": null}, {"expires_at": {"$gt": bson::DateTime::now()}}],
+        };
+        let query = self.sanctions.find(filter, None).await?;
+        let sanctions: Vec<Sanction> = query.try_collect().await?;
+
+        self.sanction_cache.insert(user_id.to_owned(), sanctions.clone());
+
+        Ok(sanctions)
+    }
+
+    /// Finds a conversation from the database with the given id
+    #[instrument(level = "debug", skip(self), err)]
+    pub async fn find_conversation(&self, id: ObjectId) -> Result<Option<Conversation>, DbError> {
+        let _timer = metrics::db_timer("find_conversation");
+        self.check_available()?;
+
+        let query = self.conversations.find_one(doc! {"_id": id}, None).await?;
+
+        Ok(query)
+    }
+
+    /// Stores a report, returning it with its new id
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// let db = Database::new(&config.database).await?;
+    /// let report = db.add_report(Report {
+    ///     id: None,
+    ///     conversation_id: conversation.id.unwrap(),
+    ///     room_id: conversation.room_id,
+    ///     reported_by: "user2".to_owned(),
+    ///     reason: "Spam".to_owned(),
+    ///     status: ReportStatus::Open,
+    ///     created_at: SystemTime::now().into(),
+    ///     resolved_by: None,
+    /// }).await?;
+    /// ```
+    #[instrument(level = "debug", skip(self, report), fields(room_id = %report.room_id), err)]
+    pub async fn add_report(&self, report: Report) -> Result<Report, DbError> {
+        let _timer = metrics::db_timer("add_report");
+        self.check_available()?;
+
+        let result = self.reports.insert_one(report.clone(), None).await?;
+
+        Ok(Report { id: result.inserted_id.as_object_id(), ..report })
+    }
+
+    /// Finds a report from the database with the given id
+    #[instrument(level = "debug", skip(self), err)]
+    pub async fn find_report(&self, id: ObjectId) -> Result<Option<Report>, DbError> {
+        let _timer = metrics::db_timer("find_report");
+        self.check_available()?;
+
+        let query = self.reports.find_one(doc! {"_id": id}, None).await?;
+
+        Ok(query)
+    }
+
+    /// Retrieves reports matching the query, oldest first
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// let db = Database::new(&config.database).await?;
+    /// let open = db.find_reports(ReportQuery {
+    ///     room_id: Some("main".to_owned()),
+    ///     status: Some(ReportStatus::Open),
+    /// }).await?;
+    /// ```
+    #[instrument(level = "debug", skip(self), err)]
+    pub async fn find_reports(&self, query: ReportQuery) -> Result<Vec<Report>, DbError> {
+        let _timer = metrics::db_timer("find_reports");
+        self.check_available()?;
+
+        let mut filter = doc! {};
+        if let Some(room_id) = query.room_id {
+            filter.insert("room_id", room_id);
+        }
+        if let Some(status) = query.status {
+            filter.insert("status", bson::to_bson(&status)?);
+        }
+
+        let options = FindOptions::builder().sort(doc! {"created_at": 1}).build();
+        let cursor = self.reports.find(filter, options).await?;
+        let reports: Vec<Report> = cursor.try_collect().await?;
+
+        Ok(reports)
+    }
+
+    /// Moves a report to a new status, returning the updated report if it exists
+    #[instrument(level = "debug", skip(self), err)]
+    pub async fn update_report_status(&self, id: ObjectId, status: ReportStatus, resolved_by: &str) -> Result<Option<Report>, DbError> {
+        let _timer = metrics::db_timer("update_report_status");
+        self.check_available()?;
+
+        let update = doc! {"$set": {"status": bson::to_bson(&status)?, "resolved_by": resolved_by}};
+        self.reports.update_one(doc! {"_id": id}, update, None).await?;
+
+        self.find_report(id).await
+    }
+
+    /// Stores a pinned announcement, returning it with its new id
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// let db = Database::new(&config.database).await?;
+    /// let announcement = db.add_announcement(Announcement {
+    ///     id: None,
+    ///     message: "Maintenance at 22:00 UTC".to_owned(),
+    ///     rooms: None,
+    ///     created_at: SystemTime::now().into(),
+    /// }).await?;
+    /// ```
+    #[instrument(level = "debug", skip(self, announcement), err)]
+    pub async fn add_announcement(&self, announcement: Announcement) -> Result<Announcement, DbError> {
+        let _timer = metrics::db_timer("add_announcement");
+        self.check_available()?;
+
+        let result = self.announcements.insert_one(announcement.clone(), None).await?;
+
+        Ok(Announcement { id: result.inserted_id.as_object_id(), ..announcement })
+    }
+
+    /// Retrieves the pinned announcements shown in a room, or every announcement without a room, newest first
+    #[instrument(level = "debug", skip(self), err)]
+    pub async fn pinned_announcements(&self, room_id: Option<&str>) -> Result<Vec<Announcement>, DbError> {
+        let _timer = metrics::db_timer("pinned_announcements");
+        self.check_available()?;
+
+        let filter = match room_id {
+            Some(room_id) => doc! {"$or": [{"rooms": null}, {"rooms": room_id}]},
+            None => doc! {},
+        };
+
+        let options = FindOptions::builder().sort(doc! {"created_at": -1}).build();
+        let cursor = self.announcements.find(filter, options).await?;
+        let announcements: Vec<Announcement> = cursor.try_collect().await?;
+
+        Ok(announcements)
+    }
+
+    /// Unpins an announcement, returning whether it existed
+    #[instrument(level = "debug", skip(self), err)]
+    pub async fn delete_announcement(&self, id: ObjectId) -> Result<bool, DbError> {
+        let _timer = metrics::db_timer("delete_announcement");
+        self.check_available()?;
+
+        let result = self.announcements.delete_one(doc! {"_id": id}, None).await?;
+
+        Ok(result.deleted_count > 0)
+    }
+
+    /// Checks that the database answers a ping within `timeout`, returning how long it took
+    #[instrument(level = "debug", skip(self), err)]
+    pub async fn ping(&self, timeout: Duration) -> Result<Duration, DbError> {
+        let _timer = metrics::db_timer("ping");
+
+        let start = Instant::now();
+        actix_rt::time::timeout(timeout, self.client_db.run_command(doc! {"ping": 1}, None)).await??;
+
+        Ok(start.elapsed())
+    }
+
+    /// Returns the hit and miss counters of the user and room caches
+    pub fn cache_stats(&self) -> (CacheStats, CacheStats) {
+        (self.user_cache.stats(), self.room_cache.stats())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn known_tokens_are_served_while_the_store_is_down() {
+        let db = Database::unavailable().await;
+        db.known_tokens.insert("known".to_owned(), "user1".to_owned());
+
+        assert_eq!(db.find_token_user("known").await.unwrap(), Some("user1".to_owned()));
+        assert!(is_unavailable(&db.find_token_user("unknown").await.unwrap_err()));
+    }
+}
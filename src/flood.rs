@@ -0,0 +1,231 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::FloodConfig;
+use crate::ratelimit::TokenBucket;
+
+/// Number of tracked users after which idle ones are forgotten
+const USER_SWEEP_THRESHOLD: usize = 10_000;
+
+/// The kind of frame being checked, each with its own budget
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameKind {
+    Text,
+    Typing,
+}
+
+/// What the session should do with a frame
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Drop the frame and tell the client why
+    Reject(String),
+    /// Drop the frame, the client has just been muted for the given time
+    Muted(Duration),
+    /// Drop the frame without a reply, the client was already told it is muted
+    Silenced,
+    Disconnect,
+}
+
+#[derive(Debug)]
+struct UserBuckets {
+    text: TokenBucket,
+    typing: TokenBucket,
+}
+
+/// Budgets shared by all sessions of the same user
+#[derive(Debug)]
+pub struct UserLimits {
+    config: FloodConfig,
+    users: Mutex<HashMap<String, UserBuckets>>,
+}
+
+impl UserLimits {
+    pub fn new(config: FloodConfig) -> Self {
+        UserLimits {
+            config,
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn try_take(&self, user_id: &str, kind: FrameKind) -> bool {
+        let mut users = self.users.lock().unwrap();
+
+        if users.len() >= USER_SWEEP_THRESHOLD {
+            users.retain(|_, buckets| !(buckets.text.is_full() && buckets.typing.is_full()));
+        }
+
+        let buckets = users.entry(user_id.to_owned()).or_insert_with(|| UserBuckets {
+            text: TokenBucket::new(self.config.user_text),
+            typing: TokenBucket::new(self.config.user_typing),
+        });
+
+        match kind {
+            FrameKind::Text => buckets.text.try_take(),
+            FrameKind::Typing => buckets.typing.try_take(),
+        }
+    }
+}
+
+/// Flood protection state for one chat session.
+///
+/// Each violation is a strike. Enough strikes mute the session for a while, and a session
+/// that keeps getting muted is disconnected.
+#[derive(Debug)]
+pub struct FloodGuard {
+    config: FloodConfig,
+    text: TokenBucket,
+    typing: TokenBucket,
+    last_text: Option<(u64, Instant)>,
+    strikes: u32,
+    mutes: u32,
+    muted_until: Option<Instant>,
+}
+
+impl FloodGuard {
+    pub fn new(config: FloodConfig) -> Self {
+        FloodGuard {
+            config,
+            text: TokenBucket::new(config.session_text),
+            typing: TokenBucket::new(config.session_typing),
+            last_text: None,
+            strikes: 0,
+            mutes: 0,
+            muted_until: None,
+        }
+    }
+
+    /// Checks a frame from `user_id` against the session and user budgets
+    pub fn check(&mut self, users: &UserLimits, user_id: &str, kind: FrameKind, text: &str) -> Verdict {
+        if let Some(until) = self.muted_until {
+            if Instant::now() < until {
+                return Verdict::Silenced;
+            }
+            self.muted_until = None;
+        }
+
+        let session_ok = match kind {
+            FrameKind::Text => self.text.try_take(),
+            FrameKind::Typing => self.typing.try_take(),
+        };
+        if !session_ok || !users.try_take(user_id, kind) {
+            return self.strike("You are sending messages too quickly");
+        }
+
+        if kind == FrameKind::Text && self.is_duplicate(text) {
+            return self.strike("Duplicate message");
+        }
+
+        Verdict::Allow
+    }
+
+    fn is_duplicate(&mut self, text: &str) -> bool {
+        let mut hasher = DefaultHasher::new();
+        text.hash(&mut hasher);
+        let hash = hasher.finish();
+        let now = Instant::now();
+
+        let duplicate = matches!(
+            self.last_text,
+            Some((last, at)) if last == hash && now.duration_since(at) < self.config.duplicate_window()
+        );
+        self.last_text = Some((hash, now));
+
+        duplicate
+    }
+
+    fn strike(&mut self, reason: &str) -> Verdict {
+        self.strikes += 1;
+        if self.strikes < self.config.strikes_before_mute {
+            return Verdict::Reject(reason.to_owned());
+        }
+
+        self.strikes = 0;
+        self.mutes += 1;
+        if self.mutes > self.config.mutes_before_disconnect {
+            return Verdict::Disconnect;
+        }
+
+        self.muted_until = Some(Instant::now() + self.config.mute_duration());
+        Verdict::Muted(self.config.mute_duration())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratelimit::Rate;
+
+    /// Budgets that never refill during a test, with generous per-user limits unless overridden
+    fn config() -> FloodConfig {
+        FloodConfig {
+            session_text: Rate::new(2, 0.001),
+            session_typing: Rate::new(2, 0.001),
+            user_text: Rate::new(100, 0.001),
+            user_typing: Rate::new(100, 0.001),
+            duplicate_window_secs: 10,
+            strikes_before_mute: 2,
+            mute_duration_secs: 30,
+            mutes_before_disconnect: 1,
+        }
+    }
+
+    #[test]
+    fn rejects_frames_over_the_session_budget() {
+        let users = UserLimits::new(config());
+        let mut guard = FloodGuard::new(config());
+
+        assert_eq!(guard.check(&users, "alice", FrameKind::Text, "a"), Verdict::Allow);
+        assert_eq!(guard.check(&users, "alice", FrameKind::Text, "b"), Verdict::Allow);
+        assert!(matches!(guard.check(&users, "alice", FrameKind::Text, "c"), Verdict::Reject(_)));
+
+        // Typing has a budget of its own
+        assert_eq!(guard.check(&users, "alice", FrameKind::Typing, ""), Verdict::Allow);
+    }
+
+    #[test]
+    fn rejects_duplicates_within_the_window() {
+        let users = UserLimits::new(config());
+        let mut guard = FloodGuard::new(config());
+
+        assert_eq!(guard.check(&users, "alice", FrameKind::Text, "hello"), Verdict::Allow);
+        assert_eq!(guard.check(&users, "alice", FrameKind::Text, "hello"), Verdict::Reject("Duplicate message".to_owned()));
+    }
+
+    #[test]
+    fn strikes_escalate_to_a_mute_then_a_disconnect() {
+        let users = UserLimits::new(config());
+        let mut guard = FloodGuard::new(config());
+
+        guard.check(&users, "alice", FrameKind::Text, "a");
+        guard.check(&users, "alice", FrameKind::Text, "b");
+
+        assert!(matches!(guard.check(&users, "alice", FrameKind::Text, "c"), Verdict::Reject(_)));
+        assert_eq!(guard.check(&users, "alice", FrameKind::Text, "d"), Verdict::Muted(Duration::from_secs(30)));
+        assert_eq!(guard.check(&users, "alice", FrameKind::Text, "e"), Verdict::Silenced);
+
+        guard.muted_until = None;
+        assert!(matches!(guard.check(&users, "alice", FrameKind::Text, "f"), Verdict::Reject(_)));
+        assert_eq!(guard.check(&users, "alice", FrameKind::Text, "g"), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn user_budget_is_shared_between_sessions() {
+        let config = FloodConfig { user_text: Rate::new(3, 0.001), ..config() };
+        let users = UserLimits::new(config);
+        let mut first = FloodGuard::new(config);
+        let mut second = FloodGuard::new(config);
+
+        assert_eq!(first.check(&users, "alice", FrameKind::Text, "a"), Verdict::Allow);
+        assert_eq!(first.check(&users, "alice", FrameKind::Text, "b"), Verdict::Allow);
+        assert_eq!(second.check(&users, "alice", FrameKind::Text, "c"), Verdict::Allow);
+        assert!(matches!(second.check(&users, "alice", FrameKind::Text, "d"), Verdict::Reject(_)));
+
+        // Other users are unaffected
+        let mut third = FloodGuard::new(config);
+        assert_eq!(third.check(&users, "bob", FrameKind::Text, "e"), Verdict::Allow);
+    }
+}
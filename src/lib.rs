@@ -0,0 +1,22 @@
+pub mod auth;
+pub mod cache;
+pub mod commands;
+pub mod config;
+pub mod database;
+pub mod filter;
+pub mod flood;
+pub mod limits;
+pub mod metrics;
+pub mod models;
+pub mod moderation;
+pub mod outbound;
+pub mod outbox;
+pub mod persistence;
+pub mod ratelimit;
+pub mod room;
+pub mod routes;
+pub mod server;
+pub mod session;
+pub mod shutdown;
+pub mod telemetry;
+pub mod tls;
@@ -0,0 +1,135 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::Hash;
+use std::net::IpAddr;
+
+use actix_web::http::StatusCode;
+use serde::Serialize;
+
+use crate::config::ConnectionsConfig;
+
+/// What a WebSocket connection counts against, claimed at the handshake and given back when the
+/// session ends
+#[derive(Debug, Clone)]
+pub struct Reservation {
+    pub ip: Option<IpAddr>,
+    /// The user the handshake's access token belongs to
+    pub user_id: Option<String>,
+}
+
+/// Which cap a handshake ran into
+#[derive(Debug, PartialEq)]
+pub enum LimitExceeded {
+    Total(usize),
+    Ip(usize),
+    User(usize),
+}
+
+impl LimitExceeded {
+    /// The server as a whole being full is a `503`, a single client over its share a `429`
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            LimitExceeded::Total(_) => StatusCode::SERVICE_UNAVAILABLE,
+            LimitExceeded::Ip(_) | LimitExceeded::User(_) => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::Total(max) => write!(f, "The server is at its limit of {max} connections"),
+            LimitExceeded::Ip(max) => write!(f, "Your address already has the maximum of {max} connections open"),
+            LimitExceeded::User(max) => write!(f, "You already have the maximum of {max} connections open"),
+        }
+    }
+}
+
+/// Open connections in total, by address and by user, as shown on the admin endpoint
+#[derive(Debug, Default, Serialize)]
+pub struct ConnectionStats {
+    pub total: usize,
+    pub by_ip: BTreeMap<String, usize>,
+    pub by_user: BTreeMap<String, usize>,
+    pub limits: ConnectionsConfig,
+}
+
+/// Counts open connections against `ConnectionsConfig`
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    config: ConnectionsConfig,
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+    by_user: HashMap<String, usize>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(config: ConnectionsConfig) -> Self {
+        ConnectionLimiter {
+            config,
+            total: 0,
+            by_ip: HashMap::new(),
+            by_user: HashMap::new(),
+        }
+    }
+
+    /// Counts a new connection, unless it would go over one of the caps
+    pub fn reserve(&mut self, reservation: &Reservation) -> Result<(), LimitExceeded> {
+        if self.total >= self.config.max_total {
+            return Err(LimitExceeded::Total(self.config.max_total));
+        }
+
+        if let Some(ip) = &reservation.ip {
+            if self.by_ip.get(ip).copied().unwrap_or(0) >= self.config.max_per_ip {
+                return Err(LimitExceeded::Ip(self.config.max_per_ip));
+            }
+        }
+
+        if let Some(user_id) = &reservation.user_id {
+            if self.by_user.get(user_id).copied().unwrap_or(0) >= self.config.max_per_user {
+                return Err(LimitExceeded::User(self.config.max_per_user));
+            }
+        }
+
+        self.total += 1;
+        if let Some(ip) = reservation.ip {
+            *self.by_ip.entry(ip).or_default() += 1;
+        }
+        if let Some(user_id) = &reservation.user_id {
+            *self.by_user.entry(user_id.clone()).or_default() += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Gives back the slot of a connection that has closed
+    pub fn release(&mut self, reservation: &Reservation) {
+        self.total = self.total.saturating_sub(1);
+
+        if let Some(ip) = &reservation.ip {
+            decrement(&mut self.by_ip, ip);
+        }
+        if let Some(user_id) = &reservation.user_id {
+            decrement(&mut self.by_user, user_id);
+        }
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            total: self.total,
+            by_ip: self.by_ip.iter().map(|(ip, count)| (ip.to_string(), *count)).collect(),
+            by_user: self.by_user.iter().map(|(user_id, count)| (user_id.clone(), *count)).collect(),
+            limits: self.config,
+        }
+    }
+}
+
+/// Lowers a count, forgetting the key once it reaches zero
+fn decrement<K: Eq + Hash>(counts: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
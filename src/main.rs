@@ -1,48 +1,148 @@
 extern crate mongodb;
 
+use std::sync::Arc;
+
 use actix::*;
 use actix_cors::Cors;
 use actix_files::Files;
-use actix_web::{web, http, App, HttpServer};
+use actix_web::{middleware, web, http, App, HttpServer};
+use tracing_actix_web::TracingLogger;
 
-mod database;
-mod models;
-mod routes;
-mod server;
-mod session;
+use futures::future::{self, Either};
+use realtime_chatrooms::{commands, config, database, flood, metrics, outbox, persistence, ratelimit, routes, server, shutdown, telemetry, tls};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let server = server::ChatServer::new().start();
-    let server_addr = "127.0.0.1";
-    let server_port = 8080;
-    let db = database::Database::new("MONGODB_URI").await;
+    dotenv::dotenv().ok();
+    telemetry::init();
+
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            tracing::error!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    if !config.server.static_dir.is_dir() {
+        tracing::warn!(path = %config.server.static_dir.display(), "Static directory does not exist, the web client will not be served");
+    }
+
+    let db = match database::Database::new(&config.database).await {
+        Ok(db) => web::Data::new(db),
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to set up the database connection");
+            std::process::exit(1);
+        }
+    };
+    actix_rt::spawn(db.get_ref().clone().monitor(config.database.clone()));
+
+    let server = server::ChatServer::new(db.clone(), config.connections).start();
+    let (outbox, _outbox_arbiter) = outbox::Outbox::start_on_own_arbiter(db.clone(), server.clone());
+    let writer = persistence::ConversationWriter::new(db.clone(), server.clone(), outbox).start();
+    let writer_data = web::Data::new(writer.clone());
+    let user_limits = web::Data::new(flood::UserLimits::new(config.flood));
+    let commands = web::Data::new(commands::Registry::default());
+    let limiter_store = Arc::new(ratelimit::LimiterStore::default());
+    let bind = (config.server.host.clone(), config.server.port);
+    let redirect = config.tls.redirect_port.map(|port| (config.server.host.clone(), port));
+
+    let tls = match config.tls.paths() {
+        Some((cert_path, key_path)) => match tls::CertResolver::new(cert_path, key_path) {
+            Ok(resolver) => {
+                let resolver = Arc::new(resolver);
+                actix_rt::spawn(resolver.clone().watch(config.tls.reload_interval()));
+                Some(resolver.server_config())
+            }
+            Err(err) => {
+                tracing::error!(error = %err, "Failed to load TLS certificate");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let https_port = tls.is_some().then_some(config.server.port);
+
+    let workers = config.server.workers;
+    let shutdown_timeout = config.server.shutdown_timeout();
+    let config = web::Data::new(config);
+    let chat = server.clone();
     let app = HttpServer::new(move || {
+        // Matched the same way as WebSocket handshakes in `routes::chat_server`
+        let origins = config.clone();
         let cors = Cors::default()
-            .allowed_origin("http://localhost:3000")
-            .allowed_origin("http://localhost:8080")
-            .allowed_methods(vec!["GET", "POST"])
+            .allowed_origin_fn(move |origin, _req| origin.to_str().is_ok_and(|origin| origins.cors.allows(origin)))
+            .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
             .allowed_headers(vec![http::header::AUTHORIZATION, http::header::ACCEPT])
             .allowed_header(http::header::CONTENT_TYPE)
-            .max_age(3600);
+            .max_age(config.cors.max_age_secs);
 
         App::new()
             .app_data(web::Data::new(server.clone()))
-            .app_data(web::Data::new(db.clone()))
+            .app_data(db.clone())
+            .app_data(writer_data.clone())
+            .app_data(user_limits.clone())
+            .app_data(commands.clone())
+            .app_data(config.clone())
+            .wrap(ratelimit::RateLimit::new(config.rate_limit.clone(), limiter_store.clone(), db.clone()))
             .wrap(cors)
+            .wrap(metrics::HttpMetrics)
+            .wrap(middleware::Condition::new(https_port.is_some(), tls::RedirectHttps { port: https_port.unwrap_or(443) }))
+            .wrap(TracingLogger::default())
             .service(web::resource("/").to(routes::index))
             .route("/ws", web::get().to(routes::chat_server))
             .service(routes::create_user)
             .service(routes::get_user)
             .service(routes::get_conversation_by_id)
             .service(routes::get_rooms)
-            .service(Files::new("/", "./static"))
+            .service(routes::create_room)
+            .service(routes::set_owner)
+            .service(routes::issue_token)
+            .service(routes::set_role)
+            .service(routes::edit_room)
+            .service(routes::set_filters)
+            .service(routes::delete_room)
+            .service(routes::add_sanction)
+            .service(routes::add_global_sanction)
+            .service(routes::broadcast)
+            .service(routes::get_announcements)
+            .service(routes::delete_announcement)
+            .service(routes::report_conversation)
+            .service(routes::get_reports)
+            .service(routes::update_report)
+            .service(routes::get_cache_stats)
+            .service(routes::get_outbound_stats)
+            .service(routes::get_connections)
+            .service(routes::get_metrics)
+            .service(routes::healthz)
+            .service(routes::readyz)
+            .service(Files::new("/", &config.server.static_dir))
     })
-    .workers(2)
-    .bind((server_addr, server_port))?
+    .workers(workers)
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout.as_secs());
+
+    let app = match tls {
+        Some(tls) => app.bind_rustls_021(bind, tls)?,
+        None => app.bind(bind)?,
+    };
+
+    // Plain HTTP is only accepted to be redirected by `tls::RedirectHttps`
+    let app = match redirect {
+        Some(redirect) => app.bind(redirect)?,
+        None => app,
+    }
     .run();
 
-    let _ = app.await;
+    let handle = app.handle();
+    let mut app = actix_rt::spawn(app);
+
+    // Signals are handled here rather than by actix so sessions are closed and messages written before the workers stop
+    if let Either::Right((res, _)) = future::select(Box::pin(shutdown::signal()), &mut app).await {
+        let _ = writer.send(persistence::Flush).await;
+        return res.unwrap_or_else(|err| Err(std::io::Error::other(err)));
+    }
 
-    Ok(())
+    shutdown::drain(handle, chat, writer, shutdown_timeout).await;
+    app.await.unwrap_or_else(|err| Err(std::io::Error::other(err)))
 }
\ No newline at end of file
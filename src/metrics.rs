@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::Error;
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+use crate::outbound;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+static MESSAGES_BROADCAST: AtomicU64 = AtomicU64::new(0);
+static MESSAGES_PERSISTED: AtomicU64 = AtomicU64::new(0);
+static HEARTBEAT_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+
+static DB_LATENCY: Mutex<BTreeMap<&'static str, Histogram>> = Mutex::new(BTreeMap::new());
+static HTTP_LATENCY: Mutex<BTreeMap<(String, String), Histogram>> = Mutex::new(BTreeMap::new());
+
+/// Cumulative latency histogram in the Prometheus format
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+
+        for (bucket, bound) in self.buckets.iter_mut().zip(BUCKETS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (count, bound) in self.buckets.iter().zip(BUCKETS) {
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {count}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+/// Counts a chat message sent on to a room
+pub fn record_broadcast() {
+    MESSAGES_BROADCAST.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts conversations written to the database
+pub fn record_persisted(count: usize) {
+    MESSAGES_PERSISTED.fetch_add(count as u64, Ordering::Relaxed);
+}
+
+/// Counts a session dropped for missing its heartbeats
+pub fn record_heartbeat_timeout() {
+    HEARTBEAT_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Times a `Database` method, recording its latency when dropped
+pub struct DbTimer {
+    method: &'static str,
+    start: Instant,
+}
+
+pub fn db_timer(method: &'static str) -> DbTimer {
+    DbTimer {
+        method,
+        start: Instant::now(),
+    }
+}
+
+impl Drop for DbTimer {
+    fn drop(&mut self) {
+        DB_LATENCY
+            .lock()
+            .unwrap()
+            .entry(self.method)
+            .or_default()
+            .observe(self.start.elapsed());
+    }
+}
+
+/// Live counts taken from `ChatServer` when metrics are scraped
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    pub sessions: usize,
+    /// Members of every running room, keyed by room name
+    pub rooms: BTreeMap<String, usize>,
+}
+
+/// Renders every metric in the Prometheus text exposition format
+pub fn render(server: &ServerStats) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "# HELP chat_sessions_connected Chat sessions currently connected");
+    let _ = writeln!(out, "# TYPE chat_sessions_connected gauge");
+    let _ = writeln!(out, "chat_sessions_connected {}", server.sessions);
+
+    let _ = writeln!(out, "# HELP chat_room_sessions Chat sessions in each room");
+    let _ = writeln!(out, "# TYPE chat_room_sessions gauge");
+    for (room, members) in &server.rooms {
+        let _ = writeln!(out, "chat_room_sessions{{room=\"{}\"}} {members}", escape(room));
+    }
+
+    let counters = [
+        ("chat_messages_broadcast_total", "Chat messages sent on to a room", &MESSAGES_BROADCAST),
+        ("chat_messages_persisted_total", "Chat messages written to the database", &MESSAGES_PERSISTED),
+        ("chat_heartbeat_timeouts_total", "Sessions dropped for missing their heartbeats", &HEARTBEAT_TIMEOUTS),
+    ];
+    for (name, help, counter) in counters {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let _ = writeln!(out, "{name} {}", counter.load(Ordering::Relaxed));
+    }
+
+    let outbound = outbound::stats();
+    let outbound_counters = [
+        ("chat_outbound_dropped_typing_total", "Typing frames dropped for slow sessions", outbound.dropped_typing),
+        ("chat_outbound_dropped_frames_total", "Frames dropped for slow sessions", outbound.dropped_frames),
+        ("chat_outbound_evicted_sessions_total", "Sessions disconnected for falling too far behind", outbound.evicted_sessions),
+    ];
+    for (name, help, value) in outbound_counters {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let _ = writeln!(out, "{name} {value}");
+    }
+
+    let _ = writeln!(out, "# HELP db_query_duration_seconds Latency of each database method");
+    let _ = writeln!(out, "# TYPE db_query_duration_seconds histogram");
+    for (method, histogram) in DB_LATENCY.lock().unwrap().iter() {
+        histogram.render(&mut out, "db_query_duration_seconds", &format!("method=\"{method}\""));
+    }
+
+    let _ = writeln!(out, "# HELP http_request_duration_seconds Latency of HTTP requests by route");
+    let _ = writeln!(out, "# TYPE http_request_duration_seconds histogram");
+    for ((method, route), histogram) in HTTP_LATENCY.lock().unwrap().iter() {
+        let labels = format!("method=\"{method}\",route=\"{}\"", escape(route));
+        histogram.render(&mut out, "http_request_duration_seconds", &labels);
+    }
+
+    out
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Middleware recording the latency of every request under its route pattern
+#[derive(Clone, Default)]
+pub struct HttpMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for HttpMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = HttpMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(HttpMetricsMiddleware { service }))
+    }
+}
+
+pub struct HttpMetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for HttpMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let method = req.method().to_string();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+
+            // Unmatched paths share one series so scanners cannot blow up the label set
+            let route = res.request().match_pattern().unwrap_or_else(|| "unmatched".to_owned());
+            HTTP_LATENCY
+                .lock()
+                .unwrap()
+                .entry((method, route))
+                .or_default()
+                .observe(start.elapsed());
+
+            Ok(res)
+        })
+    }
+}
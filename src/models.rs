@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 use mongodb::bson::oid::ObjectId;
 use chrono::{DateTime, Utc};
@@ -23,6 +25,63 @@ pub struct Room {
     pub participant_ids: Vec<String>,
     #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     pub created_at: DateTime<Utc>, 
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// Roles above member, keyed by user id. Participants without an entry are members.
+    #[serde(default)]
+    pub roles: HashMap<String, Role>,
+    /// Content checks run on every message before it is broadcast and stored
+    #[serde(default)]
+    pub filters: RoomFilters,
+}
+
+impl Room {
+    /// Returns the role of a user in this room, or `None` if they are not a participant
+    pub fn role_of(&self, user_id: &str) -> Option<Role> {
+        if let Some(role) = self.roles.get(user_id) {
+            return Some(*role);
+        }
+
+        if self.participant_ids.iter().any(|id| id == user_id) {
+            return Some(Role::Member);
+        }
+
+        None
+    }
+}
+
+/// Per-room configuration of the message filter pipeline
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RoomFilters {
+    /// Longest message allowed, in characters
+    pub max_length: usize,
+    /// Words masked out of messages, matched case insensitively
+    pub blocked_words: Vec<String>,
+    /// Domains links may point to, including their subdomains. `None` allows every link.
+    pub allowed_domains: Option<Vec<String>>,
+    /// Replace email addresses and phone numbers with a placeholder
+    pub redact_pii: bool,
+}
+
+impl Default for RoomFilters {
+    fn default() -> Self {
+        RoomFilters {
+            max_length: 2000,
+            blocked_words: Vec::new(),
+            allowed_domains: None,
+            redact_pii: false,
+        }
+    }
+}
+
+/// The authority a user has in a room, ordered from least to most
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Member,
+    Moderator,
+    Owner,
 }
 
 /// A model for a user document in our database
@@ -42,6 +101,31 @@ pub struct NewUser {
     pub nickname: String,
 }
 
+/// A model for an access token document in our database, only the token's hash is stored
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccessToken {
+    /// SHA-256 of the token, hex encoded
+    #[serde(rename = "_id")]
+    pub hash: String,
+    pub user_id: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+/// Collection of information required to make a Room document
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NewRoom {
+    pub id: String,
+    #[serde(default)]
+    pub topic: Option<String>,
+}
+
+/// Hands a room to a new owner
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OwnerChange {
+    pub user_id: String,
+}
+
 /// Collection of information required to make a Conversation document
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NewConversation {
@@ -50,6 +134,141 @@ pub struct NewConversation {
     pub message: String,
 }
 
+/// The kinds of sanction a moderator can hand out
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SanctionKind {
+    /// Can stay in the room but cannot send messages
+    Mute,
+    /// Cannot join or send messages
+    Ban,
+}
+
+/// A model for a mute or ban document in our database
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sanction {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: String,
+    /// The room the sanction applies to, or `None` for every room
+    pub room_id: Option<String>,
+    pub kind: SanctionKind,
+    pub issued_by: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+    /// When the sanction lifts, or `None` if it is permanent
+    pub expires_at: Option<bson::DateTime>,
+}
+
+impl Sanction {
+    /// Whether the sanction has not expired yet
+    pub fn is_active(&self) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > bson::DateTime::now())
+    }
+
+    /// Whether the sanction is still in force and covers `room_id`
+    pub fn applies_to(&self, room_id: &str) -> bool {
+        self.room_id.as_deref().is_none_or(|id| id == room_id) && self.is_active()
+    }
+}
+
+/// Collection of information required to sanction a user from the HTTP API
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NewSanction {
+    pub user_id: String,
+    pub kind: SanctionKind,
+    /// How long the sanction lasts, permanent if omitted
+    pub duration_secs: Option<u64>,
+}
+
+/// Where a report is in the moderation workflow
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportStatus {
+    Open,
+    /// A moderator acted on the reported message
+    Actioned,
+    /// A moderator decided no action was needed
+    Dismissed,
+}
+
+/// A model for a report document in our database
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Report {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub conversation_id: ObjectId,
+    pub room_id: String,
+    pub reported_by: String,
+    pub reason: String,
+    pub status: ReportStatus,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+    pub resolved_by: Option<String>,
+}
+
+/// Collection of information required to report a conversation
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NewReport {
+    pub reason: String,
+}
+
+/// Moves a report to a new status
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReportUpdate {
+    pub status: ReportStatus,
+}
+
+/// Filters for listing reports
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReportQuery {
+    pub room_id: Option<String>,
+    pub status: Option<ReportStatus>,
+}
+
+/// A model for a pinned announcement document in our database
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Announcement {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub message: String,
+    /// Rooms the announcement is pinned in, or `None` for every room
+    pub rooms: Option<Vec<String>>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+/// Collection of information required to broadcast a system message
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NewAnnouncement {
+    pub message: String,
+    /// Rooms to send the message to, or every connected session when missing
+    #[serde(default)]
+    pub rooms: Option<Vec<String>>,
+    /// Also store the message as a pinned announcement
+    #[serde(default)]
+    pub pin: bool,
+}
+
+/// Filters for listing pinned announcements
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AnnouncementQuery {
+    pub room_id: Option<String>,
+}
+
+/// Changes a user's role in a room
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RoleChange {
+    pub user_id: String,
+    pub role: Role,
+}
+
+/// The editable fields of a room
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RoomEdit {
+    pub topic: Option<String>,
+}
+
 /// Represents a room and all the users associated with that room
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RoomResponse {
@@ -0,0 +1,263 @@
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use bson::oid::ObjectId;
+use serde_json::json;
+
+use crate::database::{self, Database, DbError};
+use crate::models::{Report, ReportStatus, Role, RoleChange, Room, Sanction, SanctionKind};
+
+/// Why a moderation action was refused
+#[derive(Debug)]
+pub enum ModerationError {
+    /// No credentials were given for an action that needs them
+    Unauthenticated,
+    NotFound(String),
+    Forbidden(String),
+    Invalid(String),
+    Database(DbError),
+}
+
+impl fmt::Display for ModerationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModerationError::Unauthenticated => write!(f, "You must be signed in to do that"),
+            ModerationError::NotFound(msg) | ModerationError::Forbidden(msg) | ModerationError::Invalid(msg) => write!(f, "{msg}"),
+            ModerationError::Database(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<DbError> for ModerationError {
+    fn from(err: DbError) -> Self {
+        ModerationError::Database(err)
+    }
+}
+
+impl ResponseError for ModerationError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ModerationError::Unauthenticated => StatusCode::UNAUTHORIZED,
+            ModerationError::NotFound(_) => StatusCode::NOT_FOUND,
+            ModerationError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ModerationError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ModerationError::Database(err) if database::is_unavailable(err) => StatusCode::SERVICE_UNAVAILABLE,
+            ModerationError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).body(
+            json!({
+                "error": self.status_code().as_u16(),
+                "message": self.to_string()
+            })
+            .to_string(),
+        )
+    }
+}
+
+/// Loads a room and checks that `user_id` holds at least the `required` role in it
+pub async fn authorize(db: &Database, room_id: &str, user_id: &str, required: Role) -> Result<Room, ModerationError> {
+    let room = db
+        .find_room(room_id)
+        .await?
+        .ok_or_else(|| ModerationError::NotFound(format!("No room with id: {room_id}")))?;
+
+    match room.role_of(user_id) {
+        Some(role) if role >= required => Ok(room),
+        _ => {
+            let name = match required {
+                Role::Member => "member",
+                Role::Moderator => "moderator",
+                Role::Owner => "owner",
+            };
+            Err(ModerationError::Forbidden(format!("You must be a {name} of this room to do that")))
+        }
+    }
+}
+
+/// Promotes or demotes a participant, which only the room's owner may do
+pub async fn change_role(db: &Database, room_id: &str, actor: &str, change: RoleChange) -> Result<Room, ModerationError> {
+    let mut room = authorize(db, room_id, actor, Role::Owner).await?;
+
+    if change.role == Role::Owner {
+        return Err(ModerationError::Invalid("Ownership can only be handed over by an operator".to_owned()));
+    }
+
+    match room.role_of(&change.user_id) {
+        None => return Err(ModerationError::NotFound(format!("{} is not in this room", change.user_id))),
+        Some(Role::Owner) => return Err(ModerationError::Invalid("The owner's role cannot be changed".to_owned())),
+        Some(_) => {}
+    }
+
+    match change.role {
+        Role::Member => room.roles.remove(&change.user_id),
+        role => room.roles.insert(change.user_id, role),
+    };
+
+    db.set_roles(room_id, &room.roles).await?;
+
+    Ok(room)
+}
+
+/// Makes `user_id` the owner of a room, which only operators may do. A previous owner stays on as a moderator.
+pub async fn assign_owner(db: &Database, room_id: &str, user_id: &str) -> Result<Room, ModerationError> {
+    let mut room = db
+        .find_room(room_id)
+        .await?
+        .ok_or_else(|| ModerationError::NotFound(format!("No room with id: {room_id}")))?;
+
+    if db.find_user(user_id).await?.is_none() {
+        return Err(ModerationError::NotFound(format!("No user found with username: {user_id}")));
+    }
+
+    for role in room.roles.values_mut() {
+        if *role == Role::Owner {
+            *role = Role::Moderator;
+        }
+    }
+    room.roles.insert(user_id.to_owned(), Role::Owner);
+
+    db.set_roles(room_id, &room.roles).await?;
+
+    Ok(room)
+}
+
+/// Checks that `actor` moderates the room and outranks `target` in it
+pub async fn authorize_against(db: &Database, room_id: &str, actor: &str, target: &str) -> Result<Room, ModerationError> {
+    if target.trim().is_empty() {
+        return Err(ModerationError::Invalid("A user to moderate is required".to_owned()));
+    }
+
+    let room = authorize(db, room_id, actor, Role::Moderator).await?;
+
+    if actor == target {
+        return Err(ModerationError::Invalid("You cannot moderate yourself".to_owned()));
+    }
+
+    // Users who are not in the room rank below every participant
+    if let Some(target_role) = room.role_of(target) {
+        if target_role >= room.role_of(actor).unwrap_or(Role::Member) {
+            return Err(ModerationError::Forbidden(format!("{target} has the same or a higher role than you")));
+        }
+    }
+
+    Ok(room)
+}
+
+/// Mutes or bans `target` in a room for `duration`, or permanently if it is `None`
+pub async fn sanction(db: &Database, room_id: &str, actor: &str, target: &str, kind: SanctionKind, duration: Option<Duration>) -> Result<Sanction, ModerationError> {
+    authorize_against(db, room_id, actor, target).await?;
+
+    let sanction = new_sanction(Some(room_id.to_owned()), actor, target, kind, duration);
+    Ok(db.add_sanction(sanction).await?)
+}
+
+/// Mutes or bans `target` in every room, only operators may do this
+pub async fn global_sanction(db: &Database, target: &str, kind: SanctionKind, duration: Option<Duration>) -> Result<Sanction, ModerationError> {
+    let sanction = new_sanction(None, "admin", target, kind, duration);
+    Ok(db.add_sanction(sanction).await?)
+}
+
+fn new_sanction(room_id: Option<String>, actor: &str, target: &str, kind: SanctionKind, duration: Option<Duration>) -> Sanction {
+    let now = SystemTime::now();
+
+    Sanction {
+        id: None,
+        user_id: target.to_owned(),
+        room_id,
+        kind,
+        issued_by: actor.to_owned(),
+        created_at: now.into(),
+        expires_at: duration.map(|duration| bson::DateTime::from_system_time(now + duration)),
+    }
+}
+
+/// Returns the sanction stopping `user_id` from doing something in a room, if any.
+///
+/// Bans are returned before mutes since they are the stronger of the two.
+pub async fn blocking_sanction(db: &Database, user_id: &str, room_id: &str, kinds: &[SanctionKind]) -> Result<Option<Sanction>, DbError> {
+    let mut sanctions: Vec<Sanction> = db
+        .active_sanctions(user_id)
+        .await?
+        .into_iter()
+        .filter(|sanction| kinds.contains(&sanction.kind) && sanction.applies_to(room_id))
+        .collect();
+
+    sanctions.sort_by_key(|sanction| sanction.kind != SanctionKind::Ban);
+
+    Ok(sanctions.into_iter().next())
+}
+
+/// Describes a sanction to the user it applies to
+pub fn describe(sanction: &Sanction) -> String {
+    let what = match (sanction.kind, &sanction.room_id) {
+        (SanctionKind::Ban, Some(_)) => "You are banned from this room",
+        (SanctionKind::Ban, None) => "You are banned from chatting",
+        (SanctionKind::Mute, Some(_)) => "You are muted in this room",
+        (SanctionKind::Mute, None) => "You are muted",
+    };
+
+    match sanction.expires_at {
+        Some(expires_at) => format!("{what} until {}", expires_at.to_chrono().format("%Y-%m-%d %H:%M:%S UTC")),
+        None => what.to_owned(),
+    }
+}
+
+/// Files a report against a conversation, returning it along with the users who moderate its room
+pub async fn file_report(db: &Database, conversation_id: &str, reporter: &str, reason: &str) -> Result<(Report, Vec<String>), ModerationError> {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err(ModerationError::Invalid("A reason is required".to_owned()));
+    }
+
+    let conversation = db
+        .find_conversation(parse_id(conversation_id)?)
+        .await?
+        .ok_or_else(|| ModerationError::NotFound(format!("No conversation with id: {conversation_id}")))?;
+
+    let report = db.add_report(Report {
+        id: None,
+        conversation_id: conversation.id.unwrap_or_default(),
+        room_id: conversation.room_id.clone(),
+        reported_by: reporter.to_owned(),
+        reason: reason.to_owned(),
+        status: ReportStatus::Open,
+        created_at: SystemTime::now().into(),
+        resolved_by: None,
+    }).await?;
+
+    let moderators = match db.find_room(&conversation.room_id).await? {
+        Some(room) => room
+            .roles
+            .into_iter()
+            .filter(|(_, role)| *role >= Role::Moderator)
+            .map(|(user_id, _)| user_id)
+            .collect(),
+        None => Vec::new(),
+    };
+
+    Ok((report, moderators))
+}
+
+/// Marks a report as actioned, dismissed or open again, which the moderators of its room may do
+pub async fn resolve_report(db: &Database, report_id: &str, actor: &str, status: ReportStatus) -> Result<Report, ModerationError> {
+    let id = parse_id(report_id)?;
+    let report = db
+        .find_report(id)
+        .await?
+        .ok_or_else(|| ModerationError::NotFound(format!("No report with id: {report_id}")))?;
+
+    authorize(db, &report.room_id, actor, Role::Moderator).await?;
+
+    db.update_report_status(id, status, actor)
+        .await?
+        .ok_or_else(|| ModerationError::NotFound(format!("No report with id: {report_id}")))
+}
+
+fn parse_id(id: &str) -> Result<ObjectId, ModerationError> {
+    ObjectId::parse_str(id).map_err(|_| ModerationError::Invalid(format!("{id} is not a valid id")))
+}
@@ -0,0 +1,144 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use actix::prelude::*;
+use serde::Serialize;
+
+use crate::config::OutboundConfig;
+use crate::server;
+
+static DROPPED_TYPING: AtomicU64 = AtomicU64::new(0);
+static DROPPED_FRAMES: AtomicU64 = AtomicU64::new(0);
+static EVICTED_SESSIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Process wide counters of frames that never reached their session
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct OutboundStats {
+    pub dropped_typing: u64,
+    pub dropped_frames: u64,
+    pub evicted_sessions: u64,
+}
+
+pub fn stats() -> OutboundStats {
+    OutboundStats {
+        dropped_typing: DROPPED_TYPING.load(Ordering::Relaxed),
+        dropped_frames: DROPPED_FRAMES.load(Ordering::Relaxed),
+        evicted_sessions: EVICTED_SESSIONS.load(Ordering::Relaxed),
+    }
+}
+
+/// Tells a session that frames are waiting in its queue
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Drain;
+
+/// Frames taken off a queue by its session
+pub struct Drained {
+    pub frames: Vec<server::Message>,
+    /// Set once the session fell too far behind and should be closed
+    pub evicted: bool,
+    /// Set once the server is shutting down, the session should close after writing `frames`
+    pub closing: bool,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    frames: VecDeque<server::Message>,
+    /// A `Drain` has been sent and not handled yet, so another one is not needed
+    notified: bool,
+    evicted: bool,
+    closing: bool,
+}
+
+/// The sending half of a session's bounded outbound queue.
+///
+/// Rooms and the server push frames here rather than into the session's mailbox. The session
+/// only runs while its socket is writable, so a stalled client stops draining its mailbox;
+/// bounding the queue keeps that client from growing memory without limit.
+#[derive(Debug, Clone)]
+pub struct SessionSender {
+    queue: Arc<Mutex<Queue>>,
+    policy: OutboundConfig,
+    wake: Recipient<Drain>,
+}
+
+impl SessionSender {
+    pub fn new(wake: Recipient<Drain>, policy: OutboundConfig) -> Self {
+        SessionSender {
+            queue: Arc::new(Mutex::new(Queue::default())),
+            policy,
+            wake,
+        }
+    }
+
+    /// Queues a frame for the session, applying the policy when the queue is full
+    pub fn send(&self, msg: server::Message) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.evicted {
+            return;
+        }
+
+        if queue.frames.len() >= self.policy.capacity && !self.make_room(&mut queue, &msg) {
+            return;
+        }
+
+        queue.frames.push_back(msg);
+        self.notify(&mut queue);
+    }
+
+    /// Frees a slot for `msg`, returning false if `msg` should not be queued
+    fn make_room(&self, queue: &mut Queue, msg: &server::Message) -> bool {
+        if self.policy.drop_typing_first {
+            if let Some(pos) = queue.frames.iter().position(|frame| frame.typing) {
+                queue.frames.remove(pos);
+                DROPPED_TYPING.fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+
+            if msg.typing {
+                DROPPED_TYPING.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+        }
+
+        if !self.policy.disconnect_when_full {
+            DROPPED_FRAMES.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        DROPPED_FRAMES.fetch_add(queue.frames.len() as u64 + 1, Ordering::Relaxed);
+        EVICTED_SESSIONS.fetch_add(1, Ordering::Relaxed);
+        queue.frames.clear();
+        queue.evicted = true;
+        self.notify(queue);
+
+        false
+    }
+
+    /// Asks the session to close once it has written what is already queued
+    pub fn close(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.closing = true;
+        self.notify(&mut queue);
+    }
+
+    fn notify(&self, queue: &mut Queue) {
+        if !queue.notified {
+            queue.notified = true;
+            self.wake.do_send(Drain);
+        }
+    }
+
+    /// Takes every waiting frame, called by the session when it handles `Drain`
+    pub fn drain(&self) -> Drained {
+        let mut queue = self.queue.lock().unwrap();
+        queue.notified = false;
+
+        Drained {
+            frames: queue.frames.drain(..).collect(),
+            evicted: queue.evicted,
+            closing: queue.closing,
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix::prelude::*;
+use actix_web::web;
+use chrono::{TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{database, models, server, session};
+
+const OUTBOX_PATH: &str = "./outbox.jsonl";
+/// How often the outbox looks for entries that are due for another attempt
+const RETRY_TICK: Duration = Duration::from_secs(1);
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Number of failed attempts after which a message is given up on and reported to its sender
+const MAX_ATTEMPTS: u32 = 8;
+
+/// A conversation that could not be written, as it is stored on disk
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutboxEntry {
+    pub user_id: String,
+    pub room_id: String,
+    pub message: String,
+    pub created_at_ms: i64,
+    pub attempts: u32,
+    pub next_attempt_ms: u64,
+}
+
+impl OutboxEntry {
+    pub fn new(conversation: models::Conversation) -> Self {
+        OutboxEntry {
+            user_id: conversation.user_id,
+            room_id: conversation.room_id,
+            message: conversation.message,
+            created_at_ms: conversation.created_at.timestamp_millis(),
+            attempts: 1,
+            next_attempt_ms: now_ms() + backoff(1).as_millis() as u64,
+        }
+    }
+
+    fn conversation(&self) -> models::Conversation {
+        models::Conversation {
+            id: None,
+            message: self.message.clone(),
+            user_id: self.user_id.clone(),
+            room_id: self.room_id.clone(),
+            created_at: Utc.timestamp_millis_opt(self.created_at_ms).single().unwrap_or_else(Utc::now),
+        }
+    }
+}
+
+/// Stores conversations whose write failed
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Record(pub Vec<OutboxEntry>);
+
+/// Durable queue of failed conversation writes.
+///
+/// Entries are appended to `OUTBOX_PATH` as they arrive so they survive a restart, retried with
+/// exponential backoff, and reported back to the sender once `MAX_ATTEMPTS` is reached. Entries
+/// outlive the sessions that sent them, so the sender is reached through every session of their
+/// user rather than the session id.
+///
+/// Every entry is synced to disk before it is acknowledged, so the outbox is meant to be started
+/// on an arbiter of its own with `Outbox::start_on_own_arbiter`. Otherwise the writes would stall
+/// the actors sharing its thread while the store is down, which is when messages pile up here.
+pub struct Outbox {
+    db: web::Data<database::Database>,
+    server: Addr<server::ChatServer>,
+    path: PathBuf,
+    entries: Vec<OutboxEntry>,
+    retrying: bool,
+}
+
+impl Outbox {
+    /// Creates the outbox, loading any entries left over from a previous run
+    pub fn new(db: web::Data<database::Database>, server: Addr<server::ChatServer>) -> Self {
+        let path = PathBuf::from(OUTBOX_PATH);
+        let entries = match load(&path) {
+            Ok(entries) => entries,
+            Err(err) => {
+                tracing::error!(error = %err, path = %path.display(), "Failed to read outbox");
+                Vec::new()
+            }
+        };
+
+        Outbox {
+            db,
+            server,
+            path,
+            entries,
+            retrying: false,
+        }
+    }
+
+    /// Starts the outbox on a new arbiter, returned so it can be kept alive next to the address
+    pub fn start_on_own_arbiter(db: web::Data<database::Database>, server: Addr<server::ChatServer>) -> (Addr<Self>, Arbiter) {
+        let arbiter = Arbiter::new();
+        let addr = Outbox::start_in_arbiter(&arbiter.handle(), move |_| Outbox::new(db, server));
+
+        (addr, arbiter)
+    }
+
+    fn append(&self, entries: &[OutboxEntry]) -> io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        for entry in entries {
+            writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        }
+        file.sync_data()
+    }
+
+    /// Replaces the file on disk with the current in-memory entries
+    fn rewrite(&self) -> io::Result<()> {
+        let tmp = self.path.with_extension("jsonl.tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            for entry in &self.entries {
+                writeln!(file, "{}", serde_json::to_string(entry)?)?;
+            }
+            file.sync_data()?;
+        }
+        fs::rename(tmp, &self.path)
+    }
+
+    fn retry_due(&mut self, ctx: &mut Context<Self>) {
+        // Attempts are not spent while the store is known to be down, entries wait for it to come back
+        if self.retrying || !self.db.is_available() {
+            return;
+        }
+
+        let now = now_ms();
+        let (due, waiting): (Vec<_>, Vec<_>) = mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|entry| entry.next_attempt_ms <= now);
+        self.entries = waiting;
+
+        if due.is_empty() {
+            return;
+        }
+
+        self.retrying = true;
+        let db = self.db.clone();
+
+        let attempts = async move {
+            let mut failed = Vec::new();
+            for entry in due {
+                if db.insert_conversations(vec![entry.conversation()]).await.is_err() {
+                    failed.push(entry);
+                }
+            }
+            failed
+        };
+
+        ctx.spawn(attempts.into_actor(self).map(|failed, act, _ctx| {
+            for mut entry in failed {
+                if entry.attempts >= MAX_ATTEMPTS {
+                    notify_failure(&act.server, &entry.user_id, &entry.room_id, &entry.message, "the database is unavailable");
+                    continue;
+                }
+
+                entry.attempts += 1;
+                entry.next_attempt_ms = now_ms() + backoff(entry.attempts).as_millis() as u64;
+                act.entries.push(entry);
+            }
+
+            if let Err(err) = act.rewrite() {
+                tracing::error!(error = %err, path = %act.path.display(), "Failed to rewrite outbox");
+            }
+            act.retrying = false;
+        }));
+    }
+}
+
+impl Actor for Outbox {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(RETRY_TICK, |act, ctx| act.retry_due(ctx));
+    }
+}
+
+impl Handler<Record> for Outbox {
+    type Result = ();
+
+    fn handle(&mut self, msg: Record, _ctx: &mut Self::Context) -> Self::Result {
+        if let Err(err) = self.append(&msg.0) {
+            tracing::error!(error = %err, count = msg.0.len(), "Failed to write messages to the outbox");
+        }
+        self.entries.extend(msg.0);
+    }
+}
+
+/// Tells every session of the user who sent a message that it will not be saved
+pub fn notify_failure(server: &Addr<server::ChatServer>, user_id: &str, room_id: &str, message: &str, reason: &str) {
+    server.do_send(server::NotifyUsers {
+        user_ids: vec![user_id.to_owned()],
+        msg: failure_notice(room_id, message, reason),
+    });
+}
+
+/// The ERROR frame telling a sender that their message will not be saved
+pub fn failure_notice(room_id: &str, message: &str, reason: &str) -> String {
+    json!({
+        "room_id": room_id,
+        "value": vec![format!("Message could not be saved: {reason}"), message.to_owned()],
+        "chat_type": session::ChatType::ERROR
+    }).to_string()
+}
+
+fn load(path: &PathBuf) -> io::Result<Vec<OutboxEntry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str::<OutboxEntry>(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(err) => tracing::warn!(error = %err, line = %line, "Skipping corrupt outbox entry"),
+        }
+    }
+
+    Ok(entries)
+}
+
+fn backoff(attempts: u32) -> Duration {
+    BASE_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -0,0 +1,249 @@
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::time::{Duration, SystemTime};
+
+use actix::prelude::*;
+use actix_web::web;
+use futures::channel::oneshot;
+use futures::future;
+
+use crate::{database, models, outbox, server};
+
+/// Number of buffered conversations that triggers an immediate flush
+const BATCH_SIZE: usize = 64;
+/// Longest time a conversation may sit in the buffer before it is written
+const FLUSH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Queues a conversation to be written in the next batch
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Persist {
+    /// The session that sent the message, told about it if it can never be saved
+    pub session_id: usize,
+    pub conversation: models::NewConversation,
+}
+
+/// Writes every buffered conversation immediately, resolving once the write has finished
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Flush;
+
+/// Gives up on writing to the database, handing every buffered conversation and the batch being
+/// written to the `Outbox` and stopping the writer. Resolves once the outbox has recorded them.
+///
+/// Used when a `Flush` does not finish in time during shutdown. The batch being written may still
+/// have reached the database, in which case it is saved twice rather than lost.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Abandon;
+
+/// Write-behind buffer for chat messages.
+///
+/// Conversations are buffered as they arrive and written with a single bulk insert once
+/// `BATCH_SIZE` messages are pending or `FLUSH_INTERVAL` has elapsed. Each batch is validated
+/// against the database's cached users and rooms with one lookup per distinct id, and only one
+/// batch is written at a time so messages reach the database in the order they were sent. The
+/// mailbox is never held while the database is working, messages keep being buffered during a
+/// write. Batches that fail to write are handed to the `Outbox`.
+pub struct ConversationWriter {
+    db: web::Data<database::Database>,
+    server: Addr<server::ChatServer>,
+    outbox: Addr<outbox::Outbox>,
+    pending: Vec<(usize, models::Conversation)>,
+    /// A batch is being written, the next one waits for it
+    writing: bool,
+    /// Copy of the batch being written, kept so `Abandon` can hand it over
+    in_flight: Batch,
+    /// `Flush` callers waiting for the buffer to be empty
+    flushed: Vec<oneshot::Sender<()>>,
+}
+
+impl ConversationWriter {
+    pub fn new(db: web::Data<database::Database>, server: Addr<server::ChatServer>, outbox: Addr<outbox::Outbox>) -> Self {
+        ConversationWriter {
+            db,
+            server,
+            outbox,
+            pending: Vec::with_capacity(BATCH_SIZE),
+            writing: false,
+            in_flight: Vec::new(),
+            flushed: Vec::new(),
+        }
+    }
+
+    /// Starts writing the current buffer unless a batch is already being written
+    fn write_pending(&mut self, ctx: &mut Context<Self>) {
+        if self.writing {
+            return;
+        }
+
+        if self.pending.is_empty() {
+            for waiter in self.flushed.drain(..) {
+                let _ = waiter.send(());
+            }
+            return;
+        }
+
+        self.writing = true;
+        let batch = mem::replace(&mut self.pending, Vec::with_capacity(BATCH_SIZE));
+        self.in_flight = batch.clone();
+        let db = self.db.clone();
+
+        ctx.spawn(validate(db.clone(), batch).into_actor(self).then(|res, act, _ctx| {
+            let outbox = act.outbox.clone();
+            let server = act.server.clone();
+
+            async move {
+                let batch = match res {
+                    Ok((valid, rejected)) => {
+                        for (session_id, conversation, reason) in rejected {
+                            tracing::warn!(user_id = %conversation.user_id, room_id = %conversation.room_id, "{reason}, dropping message");
+                            server.do_send(server::Direct {
+                                id: session_id,
+                                msg: outbox::failure_notice(&conversation.room_id, &conversation.message, &reason.to_lowercase()),
+                            });
+                        }
+                        valid
+                    }
+                    Err((err, batch)) => {
+                        tracing::error!(error = %err, count = batch.len(), "Failed to validate messages, moving them to the outbox");
+                        return outbox.do_send(outbox::Record(to_entries(batch)));
+                    }
+                };
+
+                let conversations = batch.iter().map(|(_, conversation)| conversation.clone()).collect();
+                if let Err(err) = db.insert_conversations(conversations).await {
+                    tracing::error!(error = %err, count = batch.len(), "Failed to persist conversations, moving them to the outbox");
+                    outbox.do_send(outbox::Record(to_entries(batch)));
+                }
+            }
+            .into_actor(act)
+        }).map(|_, act, ctx| {
+            act.writing = false;
+            act.in_flight.clear();
+
+            // Keep going while a batch is full or a `Flush` is waiting, otherwise the interval picks it up
+            if act.pending.len() >= BATCH_SIZE || !act.flushed.is_empty() {
+                act.write_pending(ctx);
+            }
+        }));
+    }
+}
+
+impl Actor for ConversationWriter {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(FLUSH_INTERVAL, |act, ctx| {
+            if !act.pending.is_empty() {
+                act.write_pending(ctx);
+            }
+        });
+    }
+}
+
+impl Handler<Persist> for ConversationWriter {
+    type Result = ();
+
+    fn handle(&mut self, msg: Persist, ctx: &mut Self::Context) -> Self::Result {
+        self.pending.push((msg.session_id, to_conversation(msg.conversation)));
+
+        if self.pending.len() >= BATCH_SIZE {
+            self.write_pending(ctx);
+        }
+    }
+}
+
+impl Handler<Flush> for ConversationWriter {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, _msg: Flush, ctx: &mut Self::Context) -> Self::Result {
+        let (tx, rx) = oneshot::channel();
+        self.flushed.push(tx);
+        self.write_pending(ctx);
+
+        Box::pin(async move {
+            let _ = rx.await;
+        })
+    }
+}
+
+impl Handler<Abandon> for ConversationWriter {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, _msg: Abandon, ctx: &mut Self::Context) -> Self::Result {
+        let mut batch = mem::take(&mut self.in_flight);
+        batch.append(&mut self.pending);
+        self.writing = false;
+
+        // Stopping cancels the write in progress, so nothing reaches the database after this
+        ctx.stop();
+
+        let outbox = self.outbox.clone();
+        Box::pin(async move {
+            if batch.is_empty() {
+                return;
+            }
+
+            tracing::warn!(count = batch.len(), "Moving unwritten messages to the outbox");
+            if let Err(err) = outbox.send(outbox::Record(to_entries(batch))).await {
+                tracing::error!(error = %err, "Failed to hand unwritten messages to the outbox");
+            }
+        })
+    }
+}
+
+type Batch = Vec<(usize, models::Conversation)>;
+
+/// Splits a batch into the conversations whose user and room exist and the ones that cannot be
+/// saved, with the reason why. Every distinct user and room is looked up once.
+async fn validate(db: web::Data<database::Database>, batch: Batch) -> Result<(Batch, Vec<(usize, models::Conversation, String)>), (database::DbError, Batch)> {
+    let user_ids: HashSet<&str> = batch.iter().map(|(_, conversation)| conversation.user_id.as_str()).collect();
+    let room_ids: HashSet<&str> = batch.iter().map(|(_, conversation)| conversation.room_id.as_str()).collect();
+
+    let users = future::try_join_all(user_ids.into_iter().map(|id| {
+        let db = &db;
+        async move { Ok::<_, database::DbError>((id.to_owned(), db.find_user(id).await?.is_some())) }
+    }));
+    let rooms = future::try_join_all(room_ids.into_iter().map(|id| {
+        let db = &db;
+        async move { Ok::<_, database::DbError>((id.to_owned(), db.find_room(id).await?.is_some())) }
+    }));
+
+    let (users, rooms): (HashMap<String, bool>, HashMap<String, bool>) = match future::try_join(users, rooms).await {
+        Ok((users, rooms)) => (users.into_iter().collect(), rooms.into_iter().collect()),
+        Err(err) => return Err((err, batch)),
+    };
+
+    let mut valid = Vec::with_capacity(batch.len());
+    let mut rejected = Vec::new();
+
+    for (session_id, conversation) in batch {
+        if !users[&conversation.user_id] {
+            rejected.push((session_id, conversation, "User does not exist".to_owned()));
+        } else if !rooms[&conversation.room_id] {
+            rejected.push((session_id, conversation, "Room does not exist".to_owned()));
+        } else {
+            valid.push((session_id, conversation));
+        }
+    }
+
+    Ok((valid, rejected))
+}
+
+fn to_entries(batch: Batch) -> Vec<outbox::OutboxEntry> {
+    batch
+        .into_iter()
+        .map(|(_, conversation)| outbox::OutboxEntry::new(conversation))
+        .collect()
+}
+
+fn to_conversation(new: models::NewConversation) -> models::Conversation {
+    models::Conversation {
+        id: None,
+        message: new.message,
+        user_id: new.user_id,
+        room_id: new.room_id,
+        created_at: SystemTime::now().into(),
+    }
+}
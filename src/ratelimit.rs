@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderMap, RETRY_AFTER};
+use actix_web::{web, Error, HttpResponse};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::auth;
+use crate::config::HttpLimitConfig;
+use crate::database::Database;
+
+/// Number of tracked clients after which refilled buckets are forgotten
+const SWEEP_THRESHOLD: usize = 10_000;
+
+/// A sustained rate with an allowance for short bursts
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rate {
+    /// Most events allowed back to back
+    pub burst: u32,
+    /// Events allowed per second once the burst is spent
+    pub per_second: f64,
+}
+
+impl Rate {
+    pub const fn new(burst: u32, per_second: f64) -> Self {
+        Rate { burst, per_second }
+    }
+
+    /// Whether the rate lets anything through, a zero burst or refill would block every request for good
+    pub fn is_valid(&self) -> bool {
+        self.burst >= 1 && self.per_second.is_finite() && self.per_second > 0.0
+    }
+}
+
+/// Classic token bucket, refilled continuously at `Rate::per_second` up to `Rate::burst`
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Rate) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate.burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.per_second).min(self.rate.burst as f64);
+        self.last = now;
+    }
+
+    /// Takes a token if one is available
+    pub fn try_take(&mut self) -> bool {
+        self.refill();
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return true;
+        }
+
+        false
+    }
+
+    /// How long until the next token is available
+    pub fn retry_after(&self) -> Duration {
+        let missing = (1.0 - self.tokens).max(0.0);
+        if self.rate.per_second <= 0.0 {
+            return Duration::MAX;
+        }
+
+        Duration::from_secs_f64(missing / self.rate.per_second)
+    }
+
+    /// Whether the bucket has refilled completely, meaning it can be forgotten
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.rate.burst as f64
+    }
+}
+
+/// In-memory bucket store for a single node
+#[derive(Debug, Default)]
+pub struct LimiterStore {
+    buckets: Mutex<HashMap<(String, String), TokenBucket>>,
+}
+
+impl LimiterStore {
+    /// Takes a token from the bucket for `client` on `route`, returning how long to wait when there is none
+    pub fn check(&self, route: &str, client: &str, rate: Rate) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= SWEEP_THRESHOLD {
+            buckets.retain(|_, bucket| !bucket.is_full());
+        }
+
+        let bucket = buckets
+            .entry((route.to_owned(), client.to_owned()))
+            .or_insert_with(|| TokenBucket::new(rate));
+
+        if bucket.try_take() {
+            return Ok(());
+        }
+
+        Err(bucket.retry_after())
+    }
+}
+
+/// Middleware rejecting clients that exceed their route's budget with `429 Too Many Requests`.
+///
+/// Every request counts against its address. Requests with a valid access token also count
+/// against the user it belongs to, looked up through the database's token cache.
+#[derive(Clone)]
+pub struct RateLimit {
+    config: Rc<HttpLimitConfig>,
+    store: Arc<LimiterStore>,
+    db: web::Data<Database>,
+}
+
+impl RateLimit {
+    /// The store is shared so every worker counts against the same buckets
+    pub fn new(config: HttpLimitConfig, store: Arc<LimiterStore>, db: web::Data<Database>) -> Self {
+        RateLimit {
+            config: Rc::new(config),
+            store,
+            db,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            config: self.config.clone(),
+            store: self.store.clone(),
+            db: self.db.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    config: Rc<HttpLimitConfig>,
+    store: Arc<LimiterStore>,
+    db: web::Data<Database>,
+}
+
+/// Takes a token from every bucket the request counts against
+async fn check(config: &HttpLimitConfig, store: &LimiterStore, db: &Database, req: &ServiceRequest) -> Result<(), Duration> {
+    let route = req.match_pattern().unwrap_or_else(|| req.path().to_owned());
+    let limit = config.routes.get(&route).unwrap_or(&config.default);
+
+    // The address is counted first so made up tokens cannot be used to hammer the token lookup
+    if let Some(addr) = req.peer_addr() {
+        store.check(&route, &format!("ip:{}", addr.ip()), limit.per_ip)?;
+    }
+
+    if let Some(user) = user_key(db, req.headers()).await {
+        store.check(&route, &user, limit.per_user)?;
+    }
+
+    Ok(())
+}
+
+/// The key of the per-user bucket: the user the access token belongs to, or the token's hash while
+/// the store cannot say. Requests without a valid token only count against their address.
+async fn user_key(db: &Database, headers: &HeaderMap) -> Option<String> {
+    let token = auth::bearer(headers)?;
+
+    match auth::identify(db, Some(&token)).await {
+        Ok(user_id) => user_id.map(|user_id| format!("user:{user_id}")),
+        Err(_) => Some(format!("token:{}", auth::hash_token(&token))),
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let config = self.config.clone();
+        let store = self.store.clone();
+        let db = self.db.clone();
+
+        Box::pin(async move {
+            if let Err(retry_after) = check(&config, &store, &db, &req).await {
+                let secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+                let res = HttpResponse::TooManyRequests()
+                    .insert_header((RETRY_AFTER, secs.to_string()))
+                    .body(
+                        json!({
+                            "error": 429,
+                            "message": format!("Too many requests, retry in {secs} seconds")
+                        })
+                        .to_string(),
+                    );
+
+                return Ok(req.into_response(res).map_into_right_body());
+            }
+
+            Ok(service.call(req).await?.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn bucket_allows_the_burst_then_refuses() {
+        let mut bucket = TokenBucket::new(Rate::new(3, 0.001));
+
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+        assert!(bucket.retry_after() > Duration::ZERO);
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let mut bucket = TokenBucket::new(Rate::new(1, 1000.0));
+
+        assert!(bucket.try_take());
+        assert!(!bucket.is_full());
+
+        thread::sleep(Duration::from_millis(5));
+        assert!(bucket.is_full());
+        assert!(bucket.try_take());
+    }
+
+    #[test]
+    fn store_keeps_a_bucket_per_route_and_client() {
+        let store = LimiterStore::default();
+        let rate = Rate::new(1, 0.001);
+
+        assert!(store.check("/rooms", "ip:1.2.3.4", rate).is_ok());
+        assert!(store.check("/rooms", "ip:1.2.3.4", rate).is_err());
+        assert!(store.check("/rooms", "ip:5.6.7.8", rate).is_ok());
+        assert!(store.check("/users/create", "ip:1.2.3.4", rate).is_ok());
+    }
+
+    #[test]
+    fn rates_must_let_something_through() {
+        assert!(Rate::new(1, 0.5).is_valid());
+        assert!(!Rate::new(0, 1.0).is_valid());
+        assert!(!Rate::new(1, 0.0).is_valid());
+        assert!(!Rate::new(1, f64::NAN).is_valid());
+    }
+}
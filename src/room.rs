@@ -0,0 +1,270 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use serde_json::json;
+
+use crate::{metrics, outbound, server, session};
+
+/// How often the typing state is sent out when it changed
+const TYPING_FLUSH_INTERVAL: Duration = Duration::from_millis(250);
+/// How long someone counts as typing after their last TYPING frame
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Adds a session to the room
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Join {
+    pub id: usize,
+    pub addr: outbound::SessionSender,
+}
+
+/// Removes a session from the room, stopping the room once nobody is left
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Leave {
+    pub id: usize,
+}
+
+/// Sends a message to every member of the room except `skip_id`
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Broadcast {
+    pub msg: server::Message,
+    pub skip_id: usize,
+}
+
+/// A message from member `id`, sent to everyone else in the room.
+///
+/// Dropped if `id` is no longer a member, e.g. when it was kicked while the message was on its way.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Post {
+    pub id: usize,
+    pub msg: server::Message,
+}
+
+/// Marks member `id` as typing, or as having stopped when `active` is false
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Typing {
+    pub id: usize,
+    pub user_id: String,
+    pub active: bool,
+}
+
+/// A single chat room and the sessions currently in it.
+///
+/// Rooms are spawned on demand by `ChatServer`, which hands their address to the sessions it
+/// moves in. Sessions post their messages and typing state here directly and the room does its
+/// own fan-out, so a busy room only occupies the arbiter it was started on.
+///
+/// Typing indicators are aggregated per room. Members get a single TYPING frame listing everyone
+/// typing, sent at most once per `TYPING_FLUSH_INTERVAL` and only when the list changed.
+#[derive(Debug)]
+pub struct ChatRoom {
+    name: String,
+    members: HashMap<usize, outbound::SessionSender>,
+    /// Members typing, with their user and when their indicator expires
+    typing: HashMap<usize, (String, Instant)>,
+    /// The typing state has to be sent on the next flush
+    typing_changed: bool,
+}
+
+impl ChatRoom {
+    pub fn new(name: impl Into<String>) -> Self {
+        ChatRoom {
+            name: name.into(),
+            members: HashMap::new(),
+            typing: HashMap::new(),
+            typing_changed: false,
+        }
+    }
+
+    fn broadcast(&self, msg: server::Message, skip_id: usize) {
+        for (id, addr) in &self.members {
+            if *id != skip_id {
+                addr.send(msg.clone());
+            }
+        }
+    }
+
+    /// Clears the typing indicator of a member, if it had one up
+    fn stop_typing(&mut self, id: usize) {
+        if self.typing.remove(&id).is_some() {
+            self.typing_changed = true;
+        }
+    }
+
+    /// Expires stale typing indicators and sends the typing state if it changed
+    fn flush_typing(&mut self) {
+        let now = Instant::now();
+
+        let before = self.typing.len();
+        self.typing.retain(|_, (_, expires)| *expires > now);
+        if self.typing.len() != before {
+            self.typing_changed = true;
+        }
+
+        if !std::mem::take(&mut self.typing_changed) {
+            return;
+        }
+
+        let users: BTreeSet<&String> = self.typing.values().map(|(user_id, _)| user_id).collect();
+        let msg = server::Message {
+            payload: json!({
+                "room_id": self.name,
+                "value": users,
+                "chat_type": session::ChatType::TYPING
+            }).to_string().into(),
+            typing: true,
+        };
+        self.broadcast(msg, 0);
+    }
+}
+
+impl Actor for ChatRoom {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(TYPING_FLUSH_INTERVAL, |act, _ctx| act.flush_typing());
+    }
+}
+
+impl Handler<Join> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: Join, _ctx: &mut Self::Context) -> Self::Result {
+        self.members.insert(msg.id, msg.addr);
+    }
+}
+
+impl Handler<Leave> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: Leave, ctx: &mut Self::Context) -> Self::Result {
+        self.members.remove(&msg.id);
+        self.stop_typing(msg.id);
+
+        if self.members.is_empty() {
+            ctx.stop();
+        }
+    }
+}
+
+impl Handler<Broadcast> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: Broadcast, _ctx: &mut Self::Context) -> Self::Result {
+        self.broadcast(msg.msg, msg.skip_id);
+    }
+}
+
+impl Handler<Post> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: Post, _ctx: &mut Self::Context) -> Self::Result {
+        if !self.members.contains_key(&msg.id) {
+            return;
+        }
+
+        // Sending a message ends the sender's typing indicator
+        self.stop_typing(msg.id);
+        metrics::record_broadcast();
+
+        self.broadcast(msg.msg, msg.id);
+    }
+}
+
+impl Handler<Typing> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: Typing, _ctx: &mut Self::Context) -> Self::Result {
+        // Only members can show up as typing
+        if !self.members.contains_key(&msg.id) {
+            return;
+        }
+
+        if !msg.active {
+            return self.stop_typing(msg.id);
+        }
+
+        // Refreshing an indicator that is already up does not change what the room sees
+        let expires = Instant::now() + TYPING_TIMEOUT;
+        if self.typing.insert(msg.id, (msg.user_id, expires)).is_none() {
+            self.typing_changed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::config::OutboundConfig;
+
+    /// Stands in for a session, keeping the payloads it drains from its queue
+    struct Sink {
+        sender: outbound::SessionSender,
+        received: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Actor for Sink {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<outbound::Drain> for Sink {
+        type Result = ();
+
+        fn handle(&mut self, _msg: outbound::Drain, _ctx: &mut Self::Context) -> Self::Result {
+            let frames = self.sender.drain().frames;
+            self.received.lock().unwrap().extend(frames.into_iter().map(|frame| frame.payload.to_string()));
+        }
+    }
+
+    fn member(room: &Addr<ChatRoom>, id: usize) -> Arc<Mutex<Vec<String>>> {
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        Sink::create(|ctx| {
+            let sender = outbound::SessionSender::new(ctx.address().recipient(), OutboundConfig::default());
+            room.do_send(Join { id, addr: sender.clone() });
+
+            Sink { sender, received: received.clone() }
+        });
+
+        received
+    }
+
+    #[actix_rt::test]
+    async fn posts_reach_the_other_members_only() {
+        let room = ChatRoom::new("main").start();
+        let alice = member(&room, 1);
+        let bob = member(&room, 2);
+
+        room.send(Post { id: 1, msg: server::Message::new("hi") }).await.unwrap();
+        // Posts from sessions that are not in the room, e.g. kicked ones, are dropped
+        room.send(Post { id: 3, msg: server::Message::new("sneaky") }).await.unwrap();
+        actix_rt::time::sleep(Duration::from_millis(20)).await;
+
+        assert!(alice.lock().unwrap().is_empty());
+        assert_eq!(*bob.lock().unwrap(), vec!["hi".to_owned()]);
+    }
+
+    #[actix_rt::test]
+    async fn typing_is_aggregated_and_cleared_by_posting() {
+        let room = ChatRoom::new("main").start();
+        let _alice = member(&room, 1);
+        let bob = member(&room, 2);
+
+        room.send(Typing { id: 1, user_id: "alice".to_owned(), active: true }).await.unwrap();
+        room.send(Typing { id: 1, user_id: "alice".to_owned(), active: true }).await.unwrap();
+        actix_rt::time::sleep(TYPING_FLUSH_INTERVAL * 2).await;
+
+        room.send(Post { id: 1, msg: server::Message::new("hi") }).await.unwrap();
+        actix_rt::time::sleep(TYPING_FLUSH_INTERVAL * 2).await;
+
+        let typing = |users: &[&str]| json!({"room_id": "main", "value": users, "chat_type": session::ChatType::TYPING}).to_string();
+        assert_eq!(*bob.lock().unwrap(), vec![typing(&["alice"]), "hi".to_owned(), typing(&[])]);
+    }
+}
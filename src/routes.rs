@@ -1,29 +1,150 @@
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
 
 use actix::*;
 use actix_files::NamedFile;
-use actix_web::{Responder, HttpRequest, web, HttpResponse, Error, post, get};
+use actix_web::{Responder, HttpRequest, web, HttpResponse, Error, post, get, put, delete};
 use actix_web_actors::ws;
+use bson::oid::ObjectId;
 use serde_json::json;
-use actix_rt::System;
 
-use crate::{database, server, session, models};
+use crate::{auth, commands, config, database, flood, limits, metrics, models, moderation, outbound, persistence, server, session};
+
+/// How long each readiness check may take before it counts as failed
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Longest a message may wait in the `ChatServer` mailbox for the node to count as ready
+const MAX_MAILBOX_DELAY: Duration = Duration::from_millis(250);
 
 /// Opens the index.html file
-pub async fn index() -> impl Responder {
-    NamedFile::open_async("./static/index.html").await.unwrap()
+pub async fn index(config: web::Data<config::Config>) -> impl Responder {
+    NamedFile::open_async(config.server.static_dir.join("index.html")).await
+}
+
+/// Answers `503` while the store is unreachable and `500` for any other database failure
+fn store_error(err: database::DbError) -> Error {
+    if database::is_unavailable(&err) {
+        actix_web::error::ErrorServiceUnavailable(err)
+    } else {
+        actix_web::error::ErrorInternalServerError(err)
+    }
+}
+
+/// The user the request's access token was issued to, `401` without a valid one
+async fn signed_in(req: &HttpRequest, db: &database::Database) -> Result<String, Error> {
+    let token = auth::bearer(req.headers());
+    let user_id = auth::identify(db, token.as_deref())
+        .await
+        .map_err(store_error)?;
+
+    user_id.ok_or_else(|| moderation::ModerationError::Unauthenticated.into())
+}
+
+/// Issues a new access token to a user, returning it alongside the user
+async fn with_token(db: &database::Database, user: models::User) -> Result<HttpResponse, Error> {
+    let token = auth::new_token();
+    db.add_token(models::AccessToken {
+        hash: auth::hash_token(&token),
+        user_id: user.id.clone(),
+        created_at: SystemTime::now().into(),
+    })
+    .await
+    .map_err(store_error)?;
+
+    let mut body = serde_json::to_value(&user).map_err(actix_web::error::ErrorInternalServerError)?;
+    body["token"] = token.into();
+
+    Ok(HttpResponse::Ok().json(body))
 }
 
-/// Starts a websocket connection
-pub async fn chat_server(req: HttpRequest, stream: web::Payload, db: web::Data<database::Database>, srv: web::Data<Addr<server::ChatServer>>) -> Result<HttpResponse, Error> {
+/// Starts a websocket connection.
+///
+/// The session chats as the user its access token was issued to, given in an `Authorization`
+/// header or a `token` query parameter. Without one it can only listen, as can sessions whose
+/// token could not be checked because the store is unreachable.
+#[allow(clippy::too_many_arguments)]
+pub async fn chat_server(req: HttpRequest, stream: web::Payload, db: web::Data<database::Database>, srv: web::Data<Addr<server::ChatServer>>, writer: web::Data<Addr<persistence::ConversationWriter>>, user_limits: web::Data<flood::UserLimits>, commands: web::Data<commands::Registry>, config: web::Data<config::Config>) -> Result<HttpResponse, Error> {
+    if let Err(reason) = auth::check_origin(req.headers(), &config.cors) {
+        tracing::warn!(reason = %reason, peer = ?req.peer_addr(), "Rejected WebSocket handshake");
+
+        let res = HttpResponse::Forbidden().body(
+            json!({
+                "error": 403,
+                "message": format!("WebSocket connection refused: {reason}")
+            })
+            .to_string(),
+        );
+
+        return Ok(res);
+    }
+
+    let token = auth::handshake_token(&req);
+    let user_id = match auth::identify(&db, token.as_deref()).await {
+        Ok(Some(user_id)) => Some(user_id),
+        Ok(None) if token.is_none() => None,
+        Ok(None) => {
+            let res = HttpResponse::Unauthorized().body(
+                json!({
+                    "error": 401,
+                    "message": "WebSocket connection refused: the access token is not valid"
+                })
+                .to_string(),
+            );
+
+            return Ok(res);
+        }
+        // Tokens verified before the store went down are still served by `find_token_user`, an
+        // unknown one cannot be checked, so the client is let in to read along but not to post
+        Err(err) => {
+            tracing::warn!(error = %err, peer = ?req.peer_addr(), "Could not verify the access token, connecting without a user");
+            None
+        }
+    };
+
+    let reservation = limits::Reservation {
+        ip: req.peer_addr().map(|addr| addr.ip()),
+        user_id: user_id.clone(),
+    };
+
+    let reserved = srv.send(server::Reserve(reservation.clone()))
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    let slot = match reserved {
+        Ok(slot) => slot,
+        Err(limit) => {
+            tracing::warn!(reason = %limit, peer = ?req.peer_addr(), user = ?reservation.user_id, "Rejected WebSocket handshake");
+
+            let res = HttpResponse::build(limit.status_code()).body(
+                json!({
+                    "error": limit.status_code().as_u16(),
+                    "message": limit.to_string()
+                })
+                .to_string(),
+            );
+
+            return Ok(res);
+        }
+    };
+
+    // The slot is owned by the session and given back when it is dropped, also if the handshake fails here
     ws::start(
         session::WsChatSession {
             id: 0,
             hb: Instant::now(),
-            room: "room1".to_string(),
-            name: None,
+            timing: config.session,
+            room: None,
+            room_addr: None,
+            restrictions: session::Restrictions::default(),
+            name: user_id.clone(),
             addr: srv.get_ref().clone(),
+            writer: writer.get_ref().clone(),
             db,
+            outbound: config.outbound,
+            sender: None,
+            flood: flood::FloodGuard::new(config.flood),
+            user_limits,
+            commands,
+            span: tracing::info_span!("session", session_id = tracing::field::Empty, user = user_id.as_deref(), room = tracing::field::Empty),
+            slot,
         }, 
         &req, 
         stream
@@ -32,24 +153,35 @@ pub async fn chat_server(req: HttpRequest, stream: web::Payload, db: web::Data<d
 
 #[post("/users/create")]
 pub async fn create_user(db: web::Data<database::Database>, form: web::Json<models::NewUser>) -> Result<HttpResponse, Error> {
-    let user = web::block(move || {
-        System::new().block_on(db.add_user(form.username.clone(), form.nickname.clone()))
-    })
-    .await?
-    .map_err(actix_web::error::ErrorUnprocessableEntity)?;
+    let form = form.into_inner();
+    let user = db.add_user(form.username, form.nickname)
+        .await
+        .map_err(|err| if database::is_unavailable(&err) { store_error(err) } else { actix_web::error::ErrorUnprocessableEntity(err) })?;
+
+    // The token is only ever shown here, the database keeps its hash
+    with_token(&db, user).await
+}
+
+/// Issues another access token to an existing user, for accounts whose token was lost
+#[post("/admin/users/{username}/tokens")]
+pub async fn issue_token(req: HttpRequest, db: web::Data<database::Database>, username: web::Path<String>) -> Result<HttpResponse, Error> {
+    if !auth::is_admin(req.headers()) {
+        return Err(moderation::ModerationError::Forbidden("Only operators can issue access tokens".to_owned()).into());
+    }
+
+    let user = db.find_user(&username)
+        .await
+        .map_err(store_error)?
+        .ok_or_else(|| moderation::ModerationError::NotFound(format!("No user found with username: {username}")))?;
 
-    Ok(HttpResponse::Ok().json(user))
+    with_token(&db, user).await
 }
 
 #[get("/users/{username}")]
 pub async fn get_user(db: web::Data<database::Database>, username: web::Path<String>) -> Result<HttpResponse, Error> {
-    let id = username.to_owned();
-
-    let user = web::block(move || {
-        System::new().block_on(db.find_user(&id))
-    })
-    .await?
-    .map_err(actix_web::error::ErrorInternalServerError)?;
+    let user = db.find_user(&username)
+        .await
+        .map_err(store_error)?;
 
     if let Some(user) = user {
         return Ok(HttpResponse::Ok().json(user));
@@ -68,12 +200,9 @@ pub async fn get_user(db: web::Data<database::Database>, username: web::Path<Str
 
 #[get("/conversations/{room_id}")]
 pub async fn get_conversation_by_id(db: web::Data<database::Database>, room_id: web::Path<String>) -> Result<HttpResponse, Error> {
-    let id = room_id.to_owned();
-    let conversations = web::block(move || {
-        System::new().block_on(db.get_conversations_by_room_id(&id))
-    })
-    .await?
-    .map_err(actix_web::error::ErrorInternalServerError)?;
+    let conversations = db.get_conversations_by_room_id(&room_id)
+        .await
+        .map_err(store_error)?;
 
     if !conversations.is_empty() {
         return Ok(HttpResponse::Ok().json(conversations));
@@ -92,11 +221,9 @@ pub async fn get_conversation_by_id(db: web::Data<database::Database>, room_id:
 
 #[get("/rooms")]
 pub async fn get_rooms(db: web::Data<database::Database>) -> Result<HttpResponse, Error> {
-    let rooms = web::block(move || {
-        System::new().block_on(db.get_all_rooms())
-    })
-    .await?
-    .map_err(actix_web::error::ErrorInternalServerError)?;
+    let rooms = db.get_all_rooms()
+        .await
+        .map_err(store_error)?;
 
     if !rooms.is_empty() {
         return Ok(HttpResponse::Ok().json(rooms));
@@ -111,4 +238,385 @@ pub async fn get_rooms(db: web::Data<database::Database>) -> Result<HttpResponse
     );
 
     Ok(res)
-}
\ No newline at end of file
+}
+
+/// Creates a room, owned by the user creating it
+#[post("/rooms")]
+pub async fn create_room(req: HttpRequest, db: web::Data<database::Database>, form: web::Json<models::NewRoom>) -> Result<HttpResponse, Error> {
+    let owner = signed_in(&req, &db).await?;
+    let form = form.into_inner();
+
+    if form.id.trim().is_empty() {
+        return Err(moderation::ModerationError::Invalid("A room id is required".to_owned()).into());
+    }
+
+    let room = db.add_room(form, &owner)
+        .await
+        .map_err(|err| if database::is_unavailable(&err) { store_error(err) } else { actix_web::error::ErrorUnprocessableEntity(err) })?;
+
+    Ok(HttpResponse::Created().json(room))
+}
+
+/// Hands a room to a new owner, for rooms without one or whose owner is gone
+#[put("/admin/rooms/{room_id}/owner")]
+pub async fn set_owner(req: HttpRequest, db: web::Data<database::Database>, room_id: web::Path<String>, form: web::Json<models::OwnerChange>) -> Result<HttpResponse, Error> {
+    if !auth::is_admin(req.headers()) {
+        return Err(moderation::ModerationError::Forbidden("Only operators can assign room owners".to_owned()).into());
+    }
+
+    let room = moderation::assign_owner(&db, &room_id, &form.user_id).await?;
+
+    Ok(HttpResponse::Ok().json(room))
+}
+
+#[post("/rooms/{room_id}/roles")]
+pub async fn set_role(req: HttpRequest, db: web::Data<database::Database>, room_id: web::Path<String>, form: web::Json<models::RoleChange>) -> Result<HttpResponse, Error> {
+    let actor = signed_in(&req, &db).await?;
+    let room = moderation::change_role(&db, &room_id, &actor, form.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(room))
+}
+
+#[put("/rooms/{room_id}")]
+pub async fn edit_room(req: HttpRequest, db: web::Data<database::Database>, room_id: web::Path<String>, form: web::Json<models::RoomEdit>) -> Result<HttpResponse, Error> {
+    let actor = signed_in(&req, &db).await?;
+    moderation::authorize(&db, &room_id, &actor, models::Role::Moderator).await?;
+
+    let room = db.update_room(&room_id, form.into_inner())
+        .await
+        .map_err(store_error)?;
+
+    Ok(HttpResponse::Ok().json(room))
+}
+
+#[put("/rooms/{room_id}/filters")]
+pub async fn set_filters(req: HttpRequest, db: web::Data<database::Database>, srv: web::Data<Addr<server::ChatServer>>, room_id: web::Path<String>, form: web::Json<models::RoomFilters>) -> Result<HttpResponse, Error> {
+    let actor = signed_in(&req, &db).await?;
+    moderation::authorize(&db, &room_id, &actor, models::Role::Moderator).await?;
+
+    let room = db.set_filters(&room_id, &form)
+        .await
+        .map_err(store_error)?;
+
+    srv.do_send(server::Refresh {
+        user_id: None,
+        room: Some(room_id.to_string()),
+    });
+
+    Ok(HttpResponse::Ok().json(room))
+}
+
+#[delete("/rooms/{room_id}")]
+pub async fn delete_room(req: HttpRequest, db: web::Data<database::Database>, room_id: web::Path<String>) -> Result<HttpResponse, Error> {
+    let actor = signed_in(&req, &db).await?;
+    moderation::authorize(&db, &room_id, &actor, models::Role::Owner).await?;
+
+    db.delete_room(&room_id)
+        .await
+        .map_err(store_error)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/rooms/{room_id}/sanctions")]
+pub async fn add_sanction(req: HttpRequest, db: web::Data<database::Database>, srv: web::Data<Addr<server::ChatServer>>, room_id: web::Path<String>, form: web::Json<models::NewSanction>) -> Result<HttpResponse, Error> {
+    let actor = signed_in(&req, &db).await?;
+    let form = form.into_inner();
+    let duration = form.duration_secs.map(Duration::from_secs);
+
+    let sanction = moderation::sanction(&db, &room_id, &actor, &form.user_id, form.kind, duration).await?;
+
+    if sanction.kind == models::SanctionKind::Ban {
+        srv.do_send(server::Kick {
+            room: room_id.to_string(),
+            user_id: form.user_id.clone(),
+            reason: format!("You were removed from {room_id}"),
+        });
+    }
+
+    srv.do_send(server::Refresh {
+        user_id: Some(form.user_id.clone()),
+        room: Some(room_id.to_string()),
+    });
+
+    let action = match sanction.kind {
+        models::SanctionKind::Mute => "mute",
+        models::SanctionKind::Ban => "ban",
+    };
+
+    srv.do_send(server::RoomEvent {
+        room: room_id.to_string(),
+        msg: json!({
+            "room_id": room_id.as_str(),
+            "value": vec![action.to_owned(), form.user_id, form.duration_secs.unwrap_or(0).to_string()],
+            "chat_type": session::ChatType::MODERATE
+        }).to_string(),
+        skip_id: 0,
+    });
+
+    Ok(HttpResponse::Ok().json(sanction))
+}
+
+#[post("/admin/sanctions")]
+pub async fn add_global_sanction(req: HttpRequest, db: web::Data<database::Database>, srv: web::Data<Addr<server::ChatServer>>, form: web::Json<models::NewSanction>) -> Result<HttpResponse, Error> {
+    if !auth::is_admin(req.headers()) {
+        return Err(moderation::ModerationError::Forbidden("Only operators can sanction users in every room".to_owned()).into());
+    }
+
+    let form = form.into_inner();
+    let sanction = moderation::global_sanction(&db, &form.user_id, form.kind, form.duration_secs.map(Duration::from_secs)).await?;
+
+    srv.do_send(server::Refresh {
+        user_id: Some(form.user_id.clone()),
+        room: None,
+    });
+
+    srv.do_send(server::GlobalSanction {
+        user_id: form.user_id,
+        kind: sanction.kind,
+        duration_secs: form.duration_secs.unwrap_or(0),
+    });
+
+    Ok(HttpResponse::Ok().json(sanction))
+}
+
+#[post("/admin/broadcast")]
+pub async fn broadcast(req: HttpRequest, db: web::Data<database::Database>, srv: web::Data<Addr<server::ChatServer>>, form: web::Json<models::NewAnnouncement>) -> Result<HttpResponse, Error> {
+    if !auth::is_admin(req.headers()) {
+        return Err(moderation::ModerationError::Forbidden("Only operators can broadcast to the server".to_owned()).into());
+    }
+
+    let form = form.into_inner();
+    let message = form.message.trim().to_owned();
+    if message.is_empty() {
+        return Err(moderation::ModerationError::Invalid("A message is required".to_owned()).into());
+    }
+
+    // Pin first so an announcement is never seen live but missing from the pinned list
+    let announcement = if form.pin {
+        let announcement = models::Announcement {
+            id: None,
+            message: message.clone(),
+            rooms: form.rooms.clone(),
+            created_at: SystemTime::now().into(),
+        };
+
+        Some(db.add_announcement(announcement)
+            .await
+            .map_err(store_error)?)
+    } else {
+        None
+    };
+
+    let delivered = srv.send(server::Announce {
+        rooms: form.rooms,
+        msg: json!({
+            "value": vec![message],
+            "chat_type": session::ChatType::SYSTEM
+        }).to_string(),
+    })
+    .await
+    .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "delivered": delivered,
+        "announcement": announcement
+    })))
+}
+
+#[get("/announcements")]
+pub async fn get_announcements(db: web::Data<database::Database>, query: web::Query<models::AnnouncementQuery>) -> Result<HttpResponse, Error> {
+    let announcements = db.pinned_announcements(query.room_id.as_deref())
+        .await
+        .map_err(store_error)?;
+
+    Ok(HttpResponse::Ok().json(announcements))
+}
+
+#[delete("/admin/announcements/{announcement_id}")]
+pub async fn delete_announcement(req: HttpRequest, db: web::Data<database::Database>, announcement_id: web::Path<String>) -> Result<HttpResponse, Error> {
+    if !auth::is_admin(req.headers()) {
+        return Err(moderation::ModerationError::Forbidden("Only operators can unpin announcements".to_owned()).into());
+    }
+
+    let id = ObjectId::parse_str(announcement_id.as_str())
+        .map_err(|_| moderation::ModerationError::Invalid(format!("{announcement_id} is not a valid id")))?;
+
+    let deleted = db.delete_announcement(id)
+        .await
+        .map_err(store_error)?;
+
+    if !deleted {
+        return Err(moderation::ModerationError::NotFound(format!("No announcement with id: {announcement_id}")).into());
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/conversations/{conversation_id}/reports")]
+pub async fn report_conversation(req: HttpRequest, db: web::Data<database::Database>, srv: web::Data<Addr<server::ChatServer>>, conversation_id: web::Path<String>, form: web::Json<models::NewReport>) -> Result<HttpResponse, Error> {
+    let reporter = signed_in(&req, &db).await?;
+    let (report, moderators) = moderation::file_report(&db, &conversation_id, &reporter, &form.reason).await?;
+
+    srv.do_send(server::NotifyUsers {
+        user_ids: moderators,
+        msg: json!({
+            "room_id": report.room_id,
+            "value": vec![report.id.map(|id| id.to_hex()).unwrap_or_default(), report.conversation_id.to_hex(), report.reason.clone()],
+            "chat_type": session::ChatType::REPORT
+        }).to_string(),
+    });
+
+    Ok(HttpResponse::Created().json(report))
+}
+
+#[get("/moderation/reports")]
+pub async fn get_reports(req: HttpRequest, db: web::Data<database::Database>, query: web::Query<models::ReportQuery>) -> Result<HttpResponse, Error> {
+    let query = query.into_inner();
+
+    // Moderators see the reports of their own room, operators can list every room's
+    match &query.room_id {
+        Some(room_id) if !auth::is_admin(req.headers()) => {
+            let actor = signed_in(&req, &db).await?;
+            moderation::authorize(&db, room_id, &actor, models::Role::Moderator).await?;
+        }
+        None if !auth::is_admin(req.headers()) => {
+            return Err(moderation::ModerationError::Forbidden("A room_id is required".to_owned()).into());
+        }
+        _ => {}
+    }
+
+    let reports = db.find_reports(query)
+        .await
+        .map_err(store_error)?;
+
+    Ok(HttpResponse::Ok().json(reports))
+}
+
+#[post("/moderation/reports/{report_id}")]
+pub async fn update_report(req: HttpRequest, db: web::Data<database::Database>, report_id: web::Path<String>, form: web::Json<models::ReportUpdate>) -> Result<HttpResponse, Error> {
+    let actor = signed_in(&req, &db).await?;
+    let report = moderation::resolve_report(&db, &report_id, &actor, form.status).await?;
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[get("/cache/stats")]
+pub async fn get_cache_stats(db: web::Data<database::Database>) -> Result<HttpResponse, Error> {
+    let (users, rooms) = db.cache_stats();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "users": users,
+        "rooms": rooms,
+    })))
+}
+
+/// Liveness, answers as long as the process is serving requests
+#[get("/healthz")]
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(json!({"status": "ok"}))
+}
+
+/// Readiness, answers `503` unless the database is reachable and `ChatServer` is keeping up
+#[get("/readyz")]
+pub async fn readyz(db: web::Data<database::Database>, srv: web::Data<Addr<server::ChatServer>>) -> HttpResponse {
+    let probe = actix_rt::time::timeout(READY_TIMEOUT, srv.send(server::Probe { sent: Instant::now() }));
+    let (store, chat_server) = futures::join!(db.ping(READY_TIMEOUT), probe);
+
+    let store = match store {
+        Ok(latency) => json!({"ok": true, "latency_ms": latency.as_millis()}),
+        Err(err) => json!({"ok": false, "error": err.to_string()}),
+    };
+
+    // Actix does not expose mailbox lengths, the time the probe spent queued stands in for depth
+    let chat_server = match chat_server {
+        Ok(Ok(delay)) if delay <= MAX_MAILBOX_DELAY => json!({"ok": true, "mailbox_delay_ms": delay.as_millis()}),
+        Ok(Ok(delay)) => json!({"ok": false, "mailbox_delay_ms": delay.as_millis()}),
+        Ok(Err(_)) => json!({"ok": false, "error": "ChatServer has stopped"}),
+        Err(_) => json!({"ok": false, "error": "ChatServer did not answer in time"}),
+    };
+
+    let ready = store["ok"] == true && chat_server["ok"] == true;
+    let body = json!({
+        "status": if ready { "ready" } else { "unavailable" },
+        "checks": {
+            "store": store,
+            "chat_server": chat_server,
+        }
+    });
+
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+#[get("/metrics")]
+pub async fn get_metrics(srv: web::Data<Addr<server::ChatServer>>) -> Result<HttpResponse, Error> {
+    let stats = srv.send(server::Stats)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render(&stats)))
+}
+
+/// Open WebSocket connections against the configured caps, for operators
+#[get("/admin/connections")]
+pub async fn get_connections(req: HttpRequest, srv: web::Data<Addr<server::ChatServer>>) -> Result<HttpResponse, Error> {
+    if !auth::is_admin(req.headers()) {
+        return Err(moderation::ModerationError::Forbidden("Only operators can see connection counts".to_owned()).into());
+    }
+
+    let stats = srv.send(server::ConnectionCounts)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(stats))
+}
+
+#[get("/outbound/stats")]
+pub async fn get_outbound_stats() -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(outbound::stats()))
+}
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App};
+
+    use super::*;
+    use crate::outbox;
+
+    #[actix_rt::test]
+    async fn handshakes_are_accepted_while_the_store_is_down() {
+        let config = config::Config::default();
+        let db = web::Data::new(database::Database::unavailable().await);
+        let srv = server::ChatServer::new(db.clone(), config.connections).start();
+        let outbox = outbox::Outbox::new(db.clone(), srv.clone()).start();
+        let writer = persistence::ConversationWriter::new(db.clone(), srv.clone(), outbox).start();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(db)
+                .app_data(web::Data::new(srv))
+                .app_data(web::Data::new(writer))
+                .app_data(web::Data::new(flood::UserLimits::new(config.flood)))
+                .app_data(web::Data::new(commands::Registry::default()))
+                .app_data(web::Data::new(config))
+                .route("/ws", web::get().to(chat_server)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/ws?token=unknown")
+            .insert_header(("upgrade", "websocket"))
+            .insert_header(("connection", "upgrade"))
+            .insert_header(("sec-websocket-version", "13"))
+            .insert_header(("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+
+        assert_eq!(res.status(), actix_web::http::StatusCode::SWITCHING_PROTOCOLS);
+    }
+}
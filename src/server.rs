@@ -1,19 +1,90 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use actix::prelude::*;
+use actix_web::web;
+use bytestring::ByteString;
 use rand::{self, rngs::ThreadRng, Rng};
 use serde_json::json;
 
-use crate::session;
+use crate::{config, database, limits, metrics, models, moderation, outbound, room, session};
 
-#[derive(Message)]
-#[rtype(result = "()")]
-pub struct Message(pub String);
+/// The room every session starts in
+pub const LOBBY: &str = "room1";
+
+/// A serialized frame for a session.
+///
+/// The payload is reference counted, so cloning it for every member of a room shares one
+/// allocation instead of copying the text.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub payload: ByteString,
+    /// Typing indicators are the first frames dropped for a session that falls behind
+    pub typing: bool,
+}
+
+impl Message {
+    pub fn new(payload: impl Into<ByteString>) -> Self {
+        Message {
+            payload: payload.into(),
+            typing: false,
+        }
+    }
+}
 
 #[derive(Message)]
 #[rtype(usize)]
 pub struct Connect {
-    pub addr: Recipient<Message>,
+    pub addr: outbound::SessionSender,
+    /// Where the server tells the session about the changes it makes to it
+    pub notices: Recipient<Notice>,
+    /// The user the handshake's access token belongs to, `None` for a session that can only listen
+    pub user_id: Option<String>,
+}
+
+/// Tells a session about a change the server made to it
+#[derive(Message)]
+#[rtype(result = "()")]
+pub enum Notice {
+    /// The session was moved into a room, given with the actor it sends its messages to, or out
+    /// of its room when `None`
+    Moved(Option<(String, Addr<room::ChatRoom>)>),
+    /// Sanctions or filters that apply to the session changed and have to be loaded again
+    Reload,
+}
+
+/// Claims a connection slot before a WebSocket handshake is accepted
+#[derive(Message)]
+#[rtype(result = "Result<ReservedSlot, limits::LimitExceeded>")]
+pub struct Reserve(pub limits::Reservation);
+
+/// Gives back a slot claimed with `Reserve`, sent by `ReservedSlot` when it is dropped
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Release(limits::Reservation);
+
+/// A connection slot claimed with `Reserve`.
+///
+/// The slot is given back when this is dropped. Sessions own theirs, so it is released when the
+/// session goes away, also when it never started or never got to `Connect`.
+#[derive(Debug)]
+pub struct ReservedSlot {
+    server: Addr<ChatServer>,
+    reservation: limits::Reservation,
+}
+
+impl Drop for ReservedSlot {
+    fn drop(&mut self) {
+        self.server.do_send(Release(self.reservation.clone()));
+    }
+}
+
+/// Counts the open connections in total, by address and by user
+pub struct ConnectionCounts;
+
+impl actix::Message for ConnectionCounts {
+    type Result = limits::ConnectionStats;
 }
 
 #[derive(Message)]
@@ -22,57 +93,243 @@ pub struct Disconnect {
     pub id: usize,
 }
 
+/// Sends a message to a single session
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct ClientMessage {
+pub struct Direct {
     pub id: usize,
     pub msg: String,
-    pub room: String,
 }
 
 pub struct ListRooms;
 
+/// Checks that the server is keeping up, returning how long the probe waited in its mailbox
+#[derive(Message)]
+#[rtype(result = "Duration")]
+pub struct Probe {
+    pub sent: Instant,
+}
+
+/// Closes every session with a "server restarting" close frame, returning how many there were
+#[derive(Message)]
+#[rtype(usize)]
+pub struct Shutdown;
+
+/// Counts the connected sessions and the members of every room
+pub struct Stats;
+
+impl actix::Message for Stats {
+    type Result = metrics::ServerStats;
+}
+
 impl actix::Message for ListRooms {
     type Result = Vec<String>;
 }
 
+/// Lists the users chatting in a room, leaving out sessions that connected without an access token
+pub struct Who {
+    pub room: String,
+}
+
+impl actix::Message for Who {
+    type Result = Vec<String>;
+}
+
+/// Moves a session into another room, refused if its user is banned from that room
 #[derive(Message)]
-#[rtype(result = "()")]
+#[rtype(result = "Result<(), moderation::ModerationError>")]
 pub struct Join {
     pub id: usize,
     pub name: String,
 }
 
+/// Removes every session of a user from a room
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Kick {
+    pub room: String,
+    pub user_id: String,
+    pub reason: String,
+}
+
+/// Applies a mute or ban issued in every room to the live sessions of its user. Every room they
+/// are in is told about it, and for a ban they are removed from it.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct GlobalSanction {
+    pub user_id: String,
+    pub kind: models::SanctionKind,
+    /// How long the sanction lasts, `0` if it is permanent
+    pub duration_secs: u64,
+}
+
+/// Tells the sessions of `user_id` in `room` to load their sanctions and filters again. A `None`
+/// matches every user or every room.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Refresh {
+    pub user_id: Option<String>,
+    pub room: Option<String>,
+}
+
+/// Sends a message to every session of the given users, whichever room they are in
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct NotifyUsers {
+    pub user_ids: Vec<String>,
+    pub msg: String,
+}
+
+/// Sends a message to every session in the given rooms, or to every session when `rooms` is `None`.
+///
+/// Returns how many sessions it was sent to.
+#[derive(Message)]
+#[rtype(usize)]
+pub struct Announce {
+    pub rooms: Option<Vec<String>>,
+    pub msg: String,
+}
+
+/// Sends a server generated message to everyone in a room except `skip_id`
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RoomEvent {
+    pub room: String,
+    pub msg: String,
+    pub skip_id: usize,
+}
+
+/// A running room actor and how many sessions are in it
+#[derive(Debug)]
+struct RoomHandle {
+    addr: Addr<room::ChatRoom>,
+    members: usize,
+}
+
+/// Registry of sessions and rooms.
+///
+/// Each room is its own `ChatRoom` actor, spawned on one of the server's arbiters when the first
+/// session joins and dropped from the registry when the last one leaves. Sessions are handed the
+/// address of their room when they are moved into it and send their messages and typing state
+/// there directly. `ChatServer` only does the bookkeeping and sends the events it generates itself.
 #[derive(Debug)]
 pub struct ChatServer {
-    sessions: HashMap<usize, Recipient<Message>>,
-    rooms: HashMap<String, HashSet<usize>>,
+    sessions: HashMap<usize, outbound::SessionSender>,
+    session_rooms: HashMap<usize, String>,
+    session_users: HashMap<usize, String>,
+    session_notices: HashMap<usize, Recipient<Notice>>,
+    connections: limits::ConnectionLimiter,
+    rooms: HashMap<String, RoomHandle>,
+    arbiters: Vec<Arbiter>,
+    next_arbiter: usize,
     rng: ThreadRng,
+    db: web::Data<database::Database>,
 }
 
 impl ChatServer {
-    pub fn new() -> Self {
-        let mut rooms = HashMap::new();
-        rooms.insert("room1".to_string(), HashSet::new());
+    pub fn new(db: web::Data<database::Database>, connections: config::ConnectionsConfig) -> Self {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
 
         ChatServer {
             sessions: HashMap::new(),
-            rooms,
+            session_rooms: HashMap::new(),
+            session_users: HashMap::new(),
+            session_notices: HashMap::new(),
+            connections: limits::ConnectionLimiter::new(connections),
+            rooms: HashMap::new(),
+            arbiters: (0..threads).map(|_| Arbiter::new()).collect(),
+            next_arbiter: 0,
             rng: rand::thread_rng(),
+            db,
         }
     }
 
-    fn send_message(&self, room: &str, message: &str, skip_id: usize) {
-        if let Some(sessions) = self.rooms.get(room) {
-            for id in sessions {
-                if *id != skip_id {
-                    if let Some(addr) = self.sessions.get(id) {
-                        addr.do_send(Message(message.to_owned()));
-                    }
-                }
+    fn send_message(&self, room: &str, message: impl Into<ByteString>, skip_id: usize) {
+        self.broadcast(room, Message::new(message), skip_id);
+    }
+
+    fn broadcast(&self, room: &str, msg: Message, skip_id: usize) {
+        if let Some(handle) = self.rooms.get(room) {
+            handle.addr.do_send(room::Broadcast { msg, skip_id });
+        }
+    }
+
+    /// Moves a session into `name`, starting the room actor if it is not running
+    fn join_room(&mut self, id: usize, name: &str) {
+        let Some(addr) = self.sessions.get(&id).cloned() else {
+            return;
+        };
+
+        self.leave_room(id);
+
+        if !self.rooms.contains_key(name) {
+            let arbiter = &self.arbiters[self.next_arbiter % self.arbiters.len()];
+            self.next_arbiter = self.next_arbiter.wrapping_add(1);
+
+            let room = room::ChatRoom::new(name);
+            let room_addr = room::ChatRoom::start_in_arbiter(&arbiter.handle(), |_| room);
+            self.rooms.insert(name.to_owned(), RoomHandle { addr: room_addr, members: 0 });
+        }
+
+        let handle = self.rooms.get_mut(name).unwrap();
+        handle.members += 1;
+        handle.addr.do_send(room::Join { id, addr });
+        let room_addr = handle.addr.clone();
+        self.session_rooms.insert(id, name.to_owned());
+        self.notify(id, Notice::Moved(Some((name.to_owned(), room_addr))));
+    }
+
+    /// Takes sessions out of their rooms, telling each one why
+    fn remove(&mut self, ids: Vec<usize>, reason: &str) {
+        for id in ids {
+            let Some(room) = self.session_rooms.get(&id).cloned() else {
+                continue;
+            };
+
+            self.leave_room(id);
+            self.notify(id, Notice::Moved(None));
+
+            if let Some(addr) = self.sessions.get(&id) {
+                addr.send(Message::new(json!({
+                    "room": room,
+                    "value": vec![reason],
+                    "chat_type": session::ChatType::MODERATE
+                }).to_string()));
             }
         }
     }
+
+    fn notify(&self, id: usize, notice: Notice) {
+        if let Some(notices) = self.session_notices.get(&id) {
+            notices.do_send(notice);
+        }
+    }
+
+    /// Takes a session out of its current room, telling the members left behind
+    fn leave_room(&mut self, id: usize) {
+        let Some(name) = self.session_rooms.remove(&id) else {
+            return;
+        };
+
+        let Some(handle) = self.rooms.get_mut(&name) else {
+            return;
+        };
+
+        handle.addr.do_send(room::Leave { id });
+        handle.members -= 1;
+
+        // The room stops itself once it processes the last `Leave`
+        if handle.members == 0 {
+            self.rooms.remove(&name);
+            return;
+        }
+
+        self.send_message(&name, json!({
+            "room": name,
+            "value": vec![format!("Someone disconnected!")],
+            "chat_type": session::ChatType::DISCONNECT
+        }).to_string(), 0);
+    }
 }
 
 impl Actor for ChatServer {
@@ -86,12 +343,13 @@ impl Handler<Connect> for ChatServer {
         let id = self.rng.gen::<usize>();
 
         self.sessions.insert(id, msg.addr);
-        self.rooms
-            .entry("room1".to_string())
-            .or_insert_with(HashSet::new)
-            .insert(id);
+        self.session_notices.insert(id, msg.notices);
+        if let Some(user_id) = msg.user_id {
+            self.session_users.insert(id, user_id);
+        }
+        self.join_room(id, LOBBY);
 
-        self.send_message("room1", &json!({
+        self.send_message(LOBBY, json!({
             "value": vec![format!("{}", id)],
             "chat_type": session::ChatType::CONNECT
         }).to_string(), 0);
@@ -104,31 +362,50 @@ impl Handler<Disconnect> for ChatServer {
     type Result = ();
 
     fn handle(&mut self, msg: Disconnect, _ctx: &mut Self::Context) -> Self::Result {
-        let mut rooms = Vec::new();
-
         if self.sessions.remove(&msg.id).is_some() {
-            for (name, sessions) in &mut self.rooms {
-                if sessions.remove(&msg.id) {
-                    rooms.push(name.to_owned());
-                }
-            }
+            self.leave_room(msg.id);
+            self.session_users.remove(&msg.id);
+            self.session_notices.remove(&msg.id);
         }
+    }
+}
 
-        for room in rooms {
-            self.send_message("room1", &json!({
-                "room": room,
-                "value": vec![format!("Someone disconnected!")],
-                "chat_type": session::ChatType::DISCONNECT
-            }).to_string(), 0);
-        }
+impl Handler<Reserve> for ChatServer {
+    type Result = Result<ReservedSlot, limits::LimitExceeded>;
+
+    fn handle(&mut self, msg: Reserve, ctx: &mut Self::Context) -> Self::Result {
+        self.connections.reserve(&msg.0)?;
+
+        Ok(ReservedSlot {
+            server: ctx.address(),
+            reservation: msg.0,
+        })
     }
 }
 
-impl Handler<ClientMessage> for ChatServer {
+impl Handler<Release> for ChatServer {
     type Result = ();
-    
-    fn handle(&mut self, msg: ClientMessage, _ctx: &mut Self::Context) -> Self::Result {
-        self.send_message(&msg.room, &msg.msg, msg.id);
+
+    fn handle(&mut self, msg: Release, _ctx: &mut Self::Context) -> Self::Result {
+        self.connections.release(&msg.0);
+    }
+}
+
+impl Handler<ConnectionCounts> for ChatServer {
+    type Result = MessageResult<ConnectionCounts>;
+
+    fn handle(&mut self, _msg: ConnectionCounts, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.connections.stats())
+    }
+}
+
+impl Handler<Direct> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Direct, _ctx: &mut Self::Context) -> Self::Result {
+        if let Some(addr) = self.sessions.get(&msg.id) {
+            addr.send(Message::new(msg.msg));
+        }
     }
 }
 
@@ -146,30 +423,193 @@ impl Handler<ListRooms> for ChatServer {
     }
 }
 
+impl Handler<Shutdown> for ChatServer {
+    type Result = usize;
+
+    fn handle(&mut self, _msg: Shutdown, _ctx: &mut Self::Context) -> Self::Result {
+        for addr in self.sessions.values() {
+            addr.close();
+        }
+
+        self.sessions.len()
+    }
+}
+
+impl Handler<Probe> for ChatServer {
+    type Result = MessageResult<Probe>;
+
+    fn handle(&mut self, msg: Probe, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(msg.sent.elapsed())
+    }
+}
+
+impl Handler<Stats> for ChatServer {
+    type Result = MessageResult<Stats>;
+
+    fn handle(&mut self, _msg: Stats, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(metrics::ServerStats {
+            sessions: self.sessions.len(),
+            rooms: self.rooms.iter().map(|(name, handle)| (name.clone(), handle.members)).collect(),
+        })
+    }
+}
+
+impl Handler<Who> for ChatServer {
+    type Result = MessageResult<Who>;
+
+    fn handle(&mut self, msg: Who, _ctx: &mut Self::Context) -> Self::Result {
+        let users: BTreeSet<&String> = self
+            .session_rooms
+            .iter()
+            .filter(|(_, room)| **room == msg.room)
+            .filter_map(|(id, _)| self.session_users.get(id))
+            .collect();
+
+        MessageResult(users.into_iter().cloned().collect())
+    }
+}
+
 impl Handler<Join> for ChatServer {
-    type Result = ();
+    type Result = ResponseActFuture<Self, Result<(), moderation::ModerationError>>;
 
     fn handle(&mut self, msg: Join, _ctx: &mut Self::Context) -> Self::Result {
         let Join {id, name} = msg;
-        let mut rooms = Vec::new();
 
-        for (n, sessions) in &mut self.rooms {
-            if sessions.remove(&id) {
-                rooms.push(n.to_owned());
+        let Some(user_id) = self.session_users.get(&id).cloned() else {
+            self.join_room(id, &name);
+            return Box::pin(fut::ready(Ok(())));
+        };
+
+        let db = self.db.clone();
+        let room = name.clone();
+        let check = async move {
+            moderation::blocking_sanction(&db, &user_id, &room, &[models::SanctionKind::Ban]).await
+        };
+
+        Box::pin(check.into_actor(self).map(move |res, act, _ctx| {
+            match res {
+                Ok(Some(ban)) => return Err(moderation::ModerationError::Forbidden(moderation::describe(&ban))),
+                Ok(None) => {}
+                // Keep chat working when bans cannot be checked, as sessions do for mutes
+                Err(err) => tracing::warn!(error = %err, room = %name, "Failed to check bans, letting the session join"),
             }
-        }
 
-        for room in rooms {
-            self.send_message(&room, &json!({
-                "room": room,
-                "value": vec![format!("Someone disconnected!")],
-                "chat_type": session::ChatType::DISCONNECT
+            act.join_room(id, &name);
+            Ok(())
+        }))
+    }
+}
+
+impl Handler<Kick> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Kick, _ctx: &mut Self::Context) -> Self::Result {
+        let kicked: Vec<usize> = self
+            .session_users
+            .iter()
+            .filter(|(id, user_id)| **user_id == msg.user_id && self.session_rooms.get(id) == Some(&msg.room))
+            .map(|(id, _)| *id)
+            .collect();
+
+        self.remove(kicked, &msg.reason);
+    }
+}
+
+impl Handler<GlobalSanction> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: GlobalSanction, _ctx: &mut Self::Context) -> Self::Result {
+        let sessions: Vec<usize> = self
+            .session_users
+            .iter()
+            .filter(|(_, user_id)| **user_id == msg.user_id)
+            .map(|(id, _)| *id)
+            .collect();
+
+        let rooms: BTreeSet<String> = sessions.iter().filter_map(|id| self.session_rooms.get(id).cloned()).collect();
+
+        let action = match msg.kind {
+            models::SanctionKind::Mute => "mute",
+            models::SanctionKind::Ban => "ban",
+        };
+
+        // Told before the sessions are removed so the room sees who left and why
+        for room in &rooms {
+            self.send_message(room, json!({
+                "room_id": room,
+                "value": vec![action.to_owned(), msg.user_id.clone(), msg.duration_secs.to_string()],
+                "chat_type": session::ChatType::MODERATE
             }).to_string(), 0);
         }
 
-        self.rooms
-            .entry(name.clone())
-            .or_insert_with(HashSet::new)
-            .insert(id);
+        if msg.kind == models::SanctionKind::Ban {
+            self.remove(sessions, "You are banned from chatting");
+        }
+    }
+}
+
+impl Handler<Refresh> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Refresh, _ctx: &mut Self::Context) -> Self::Result {
+        for (id, room) in &self.session_rooms {
+            let user_matches = match &msg.user_id {
+                Some(user_id) => self.session_users.get(id) == Some(user_id),
+                None => true,
+            };
+
+            if user_matches && msg.room.as_ref().is_none_or(|name| name == room) {
+                self.notify(*id, Notice::Reload);
+            }
+        }
+    }
+}
+
+impl Handler<NotifyUsers> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: NotifyUsers, _ctx: &mut Self::Context) -> Self::Result {
+        let frame = Message::new(msg.msg);
+
+        for (id, user_id) in &self.session_users {
+            if msg.user_ids.contains(user_id) {
+                if let Some(addr) = self.sessions.get(id) {
+                    addr.send(frame.clone());
+                }
+            }
+        }
+    }
+}
+
+impl Handler<Announce> for ChatServer {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Announce, _ctx: &mut Self::Context) -> Self::Result {
+        let frame = Message::new(msg.msg);
+
+        let Some(rooms) = msg.rooms else {
+            for addr in self.sessions.values() {
+                addr.send(frame.clone());
+            }
+            return self.sessions.len();
+        };
+
+        let mut delivered = 0;
+        for name in rooms.iter().collect::<BTreeSet<_>>() {
+            if let Some(handle) = self.rooms.get(name) {
+                delivered += handle.members;
+                self.broadcast(name, frame.clone(), 0);
+            }
+        }
+
+        delivered
+    }
+}
+
+impl Handler<RoomEvent> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RoomEvent, _ctx: &mut Self::Context) -> Self::Result {
+        self.send_message(&msg.room, msg.msg, msg.skip_id);
     }
 }
\ No newline at end of file
@@ -5,32 +5,90 @@ use actix_web::web;
 use actix_web_actors::ws;
 
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::{database, server, models};
+use crate::{commands, config, database, filter, flood, metrics, models, moderation, outbound, persistence, room, server};
 
-const HEARTBEAT: Duration = Duration::from_secs(5);
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Sent back for messages from a session that was removed from its room
+pub const NOT_IN_A_ROOM: &str = "You are not in a room, use /join <room> to enter one";
 
 #[derive(Debug)]
 pub struct WsChatSession {
     pub id: usize,
     pub hb: Instant,
-    pub room: String,
+    /// Heartbeat timing from the configuration
+    pub timing: config::SessionConfig,
+    /// The room the server has the session in, as last told with `server::Notice::Moved`. `None`
+    /// before the session is connected and after it was kicked, until it joins another room.
+    pub room: Option<String>,
+    /// The actor of `room`, which messages and typing state are sent to
+    pub room_addr: Option<Addr<room::ChatRoom>>,
+    /// What messages are checked against in `room`
+    pub restrictions: Restrictions,
+    /// The user the handshake's access token belongs to, `None` if the session can only listen
     pub name: Option<String>,
     pub addr: Addr<server::ChatServer>,
+    pub writer: Addr<persistence::ConversationWriter>,
     pub db: web::Data<database::Database>,
+    /// Queue policy from the configuration
+    pub outbound: config::OutboundConfig,
+    /// Queue that rooms deliver into, created once the session has an address
+    pub sender: Option<outbound::SessionSender>,
+    pub flood: flood::FloodGuard,
+    pub user_limits: web::Data<flood::UserLimits>,
+    pub commands: web::Data<commands::Registry>,
+    /// Connection slot claimed at the handshake, given back when the session is dropped
+    pub slot: server::ReservedSlot,
+    /// Covers the whole life of the session, recording its id, user and room as they become known
+    pub span: tracing::Span,
 }
 
+/// The sanctions and filters messages from a session are checked against in its room.
+///
+/// Loaded whenever the session is moved into a room and again when `ChatServer` says they
+/// changed, so sending a message does not wait on the database.
+#[derive(Debug, Default)]
+pub struct Restrictions {
+    /// The room these were loaded for
+    pub room: Option<String>,
+    /// Mutes and bans on the session's user that apply to its room
+    pub sanctions: Vec<models::Sanction>,
+    /// `None` while the room's filters could not be loaded, messages are refused until they are
+    pub filters: Option<models::RoomFilters>,
+}
+
+impl Restrictions {
+    /// The sanction stopping the user from sending messages, bans before mutes
+    pub fn blocking(&self) -> Option<&models::Sanction> {
+        self.sanctions
+            .iter()
+            .filter(|sanction| sanction.is_active())
+            .min_by_key(|sanction| sanction.kind != models::SanctionKind::Ban)
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Serialize, Deserialize)]
 pub enum ChatType {
+    /// Sent by clients with a `value` of `["IN"]` while typing and `["OUT"]` once they stop. Rooms
+    /// receive the users currently typing as `value`, and an empty list when nobody is.
     TYPING,
     TEXT,
     CONNECT,
     DISCONNECT,
+    ERROR,
+    /// A moderation command, `value` holds the action followed by its arguments
+    MODERATE,
+    /// A message in a room you moderate was reported
+    REPORT,
+    /// The outcome of a slash command, `value` holds the command name followed by its output
+    COMMAND,
+    /// A notice from the operators of the server
+    SYSTEM,
 }
 
 #[derive(Serialize, Deserialize)]
-struct ChatMessage {
+pub struct ChatMessage {
     pub chat_type: ChatType,
     pub value: Vec<String>,
     pub room_id: String,
@@ -40,8 +98,10 @@ struct ChatMessage {
 
 impl WsChatSession {
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT, |act, ctx| {
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+        ctx.run_interval(self.timing.heartbeat_interval(), |act, ctx| {
+            if Instant::now().duration_since(act.hb) > act.timing.client_timeout() {
+                metrics::record_heartbeat_timeout();
+                tracing::info!(parent: &act.span, "Heartbeat timed out");
                 act.addr.do_send(server::Disconnect { id: act.id });
                 ctx.stop();
                 return;
@@ -50,6 +110,283 @@ impl WsChatSession {
             ctx.ping(b"");
         });
     }
+
+    /// Runs a frame from `user_id` through flood protection, replying to the client when it is dropped
+    fn allow(&mut self, user_id: &str, input: &ChatMessage, kind: flood::FrameKind, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        let text = match kind {
+            flood::FrameKind::Text => input.value.join(""),
+            flood::FrameKind::Typing => String::new(),
+        };
+
+        match self.flood.check(&self.user_limits, user_id, kind, &text) {
+            flood::Verdict::Allow => true,
+
+            flood::Verdict::Reject(reason) => {
+                self.send_error(&input.room_id, &reason, ctx);
+                false
+            }
+
+            flood::Verdict::Muted(duration) => {
+                self.send_error(&input.room_id, &format!("You have been muted for {} seconds", duration.as_secs()), ctx);
+                false
+            }
+
+            flood::Verdict::Silenced => false,
+
+            flood::Verdict::Disconnect => {
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Policy,
+                    description: Some("Flooding".to_owned()),
+                }));
+                ctx.stop();
+                false
+            }
+        }
+    }
+
+    /// Runs a moderation command in the session's room against the database, then tells the room and
+    /// replies with the outcome
+    fn moderate(&mut self, actor: &str, input: &ChatMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        let Some(room_id) = self.room.clone() else {
+            return self.send_error(&input.room_id, NOT_IN_A_ROOM, ctx);
+        };
+
+        let args = input.value.clone();
+        let usage = match args.first().map(String::as_str) {
+            Some(action @ ("promote" | "demote" | "kick")) => format!("Usage: {action} <user>"),
+            Some(action @ ("mute" | "ban")) => format!("Usage: {action} <user> [seconds]"),
+            _ => return self.send_error(&room_id, "Unknown moderation action", ctx),
+        };
+
+        let target = match args.get(1).map(|target| target.trim()) {
+            Some(target) if !target.is_empty() => target.to_owned(),
+            _ => return self.send_error(&room_id, &usage, ctx),
+        };
+
+        if target == actor {
+            return self.send_error(&room_id, "You cannot moderate yourself", ctx);
+        }
+
+        let db = self.db.clone();
+        let actor = actor.to_owned();
+
+        let action = {
+            let room_id = room_id.clone();
+            let args = args.clone();
+            let target = target.clone();
+
+            async move {
+                match args.first().map(String::as_str) {
+                    Some("promote") => {
+                        let change = models::RoleChange { user_id: target, role: models::Role::Moderator };
+                        moderation::change_role(&db, &room_id, &actor, change).await.map(|_| false)
+                    }
+
+                    Some("demote") => {
+                        let change = models::RoleChange { user_id: target, role: models::Role::Member };
+                        moderation::change_role(&db, &room_id, &actor, change).await.map(|_| false)
+                    }
+
+                    Some("kick") => moderation::authorize_against(&db, &room_id, &actor, &target).await.map(|_| true),
+
+                    Some("mute") => {
+                        let duration = parse_duration(args.get(2))?;
+                        moderation::sanction(&db, &room_id, &actor, &target, models::SanctionKind::Mute, duration).await.map(|_| false)
+                    }
+
+                    Some("ban") => {
+                        let duration = parse_duration(args.get(2))?;
+                        moderation::sanction(&db, &room_id, &actor, &target, models::SanctionKind::Ban, duration).await.map(|_| true)
+                    }
+
+                    _ => Err(moderation::ModerationError::Invalid("Unknown moderation action".to_owned())),
+                }
+            }
+        };
+
+        ctx.spawn(action.into_actor(self).map(move |res, act, ctx| {
+            let remove = match res {
+                Ok(remove) => remove,
+                Err(err) => return act.send_error(&room_id, &err.to_string(), ctx),
+            };
+
+            if remove {
+                act.addr.do_send(server::Kick {
+                    room: room_id.clone(),
+                    user_id: target.clone(),
+                    reason: format!("You were removed from {room_id}"),
+                });
+            }
+
+            if matches!(args.first().map(String::as_str), Some("mute" | "ban")) {
+                act.addr.do_send(server::Refresh {
+                    user_id: Some(target),
+                    room: Some(room_id.clone()),
+                });
+            }
+
+            let event = json!({
+                "room_id": room_id,
+                "value": args,
+                "chat_type": ChatType::MODERATE
+            }).to_string();
+
+            act.addr.do_send(server::RoomEvent {
+                room: room_id,
+                msg: event.clone(),
+                skip_id: act.id,
+            });
+            ctx.text(event);
+        }));
+    }
+
+    /// Broadcasts and stores a text message in the session's room unless the sender is muted or
+    /// banned there or the message does not pass the room's filters
+    pub fn send_text(&mut self, mut chat_msg: ChatMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        let (Some(room_id), Some(room_addr)) = (self.room.clone(), self.room_addr.clone()) else {
+            return self.send_error(&chat_msg.room_id, NOT_IN_A_ROOM, ctx);
+        };
+
+        // Whatever room the client named, the message goes to and is checked against the one it is in
+        chat_msg.room_id = room_id;
+
+        if let Some(sanction) = self.restrictions.blocking() {
+            return self.send_error(&chat_msg.room_id, &moderation::describe(sanction), ctx);
+        }
+
+        let Some(filters) = &self.restrictions.filters else {
+            self.send_error(&chat_msg.room_id, "The filters of this room could not be loaded, try again in a moment", ctx);
+            return self.load_restrictions(ctx);
+        };
+
+        // Filtered as one text, the way it is stored, so splitting words across parts does not get past the filters
+        let pipeline = filter::Pipeline::for_room(filters);
+        let message = match pipeline.run(chat_msg.value.join("")) {
+            Ok(message) => message,
+            Err(reason) => return self.send_error(&chat_msg.room_id, &reason, ctx),
+        };
+        chat_msg.value = vec![message.clone()];
+
+        let conversation = models::NewConversation {
+            user_id: chat_msg.user_id.clone(),
+            room_id: chat_msg.room_id.clone(),
+            message,
+        };
+
+        room_addr.do_send(room::Post {
+            id: self.id,
+            msg: server::Message::new(serde_json::to_string(&chat_msg).unwrap()),
+        });
+
+        self.writer.do_send(persistence::Persist {
+            session_id: self.id,
+            conversation,
+        });
+    }
+
+    /// Loads the sanctions and filters that apply in the session's room, holding later frames back
+    /// until they are known
+    fn load_restrictions(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let (Some(user_id), Some(room_id)) = (self.name.clone(), self.room.clone()) else {
+            self.restrictions = Restrictions::default();
+            return;
+        };
+
+        let db = self.db.clone();
+        let load = async move {
+            let sanctions = db.active_sanctions(&user_id).await.map(|sanctions| {
+                let kinds = [models::SanctionKind::Ban, models::SanctionKind::Mute];
+                sanctions
+                    .into_iter()
+                    .filter(|sanction| kinds.contains(&sanction.kind) && sanction.applies_to(&room_id))
+                    .collect::<Vec<_>>()
+            });
+            let filters = db.find_room(&room_id).await.map(|room| room.map(|room| room.filters).unwrap_or_default());
+            (room_id, sanctions, filters)
+        };
+
+        ctx.wait(load.into_actor(self).map(|(room_id, sanctions, filters), act, _ctx| {
+            let reloading = act.restrictions.room.as_deref() == Some(room_id.as_str());
+
+            let sanctions = match sanctions {
+                Ok(sanctions) => sanctions,
+                Err(err) if reloading => {
+                    tracing::warn!(parent: &act.span, error = %err, "Failed to reload sanctions, keeping the last known ones");
+                    std::mem::take(&mut act.restrictions.sanctions)
+                }
+                // Keep chat working when sanctions cannot be checked
+                Err(err) => {
+                    tracing::warn!(parent: &act.span, error = %err, "Failed to load sanctions, letting messages through");
+                    Vec::new()
+                }
+            };
+
+            let filters = match filters {
+                Ok(filters) => Some(filters),
+                // Messages are not let through unfiltered, the room's last known filters stay in use if there are any
+                Err(err) if reloading && act.restrictions.filters.is_some() => {
+                    tracing::warn!(parent: &act.span, error = %err, "Failed to reload the room's filters, keeping the last known ones");
+                    act.restrictions.filters.take()
+                }
+                Err(err) => {
+                    tracing::warn!(parent: &act.span, error = %err, "Failed to load the room's filters, refusing messages until they load");
+                    None
+                }
+            };
+
+            act.restrictions = Restrictions {
+                room: Some(room_id),
+                sanctions,
+                filters,
+            };
+        }));
+    }
+
+    /// Handles a TEXT or MODERATE frame
+    fn dispatch(&mut self, user_id: String, input: ChatMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        if input.chat_type == ChatType::MODERATE {
+            return self.moderate(&user_id, &input, ctx);
+        }
+
+        let text = input.value.join("");
+        if let Some((name, rest)) = commands::parse(&text) {
+            return self.run_command(&user_id, &input, name, rest, ctx);
+        }
+
+        let chat_msg = ChatMessage {
+            chat_type: ChatType::TEXT,
+            value: input.value,
+            room_id: input.room_id,
+            user_id,
+            id: self.id,
+        };
+
+        self.send_text(chat_msg, ctx);
+    }
+
+    /// Runs a slash command from the registry, or tells the client it does not exist
+    fn run_command(&mut self, user_id: &str, input: &ChatMessage, name: &str, rest: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let commands = self.commands.clone();
+        let Some(command) = commands.find(name) else {
+            return self.send_error(&input.room_id, &format!("Unknown command /{name}, try /help"), ctx);
+        };
+
+        let room_id = self.room.as_deref().unwrap_or(&input.room_id);
+        (command.run)(self, commands::Invocation::new(room_id, user_id, rest), ctx);
+    }
+
+    pub fn send_error(&self, room_id: &str, reason: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.text(json!({
+            "room_id": room_id,
+            "value": vec![reason],
+            "chat_type": ChatType::ERROR
+        }).to_string());
+    }
+
+    /// Replies to the client with the output of a slash command
+    pub fn reply(&self, room_id: &str, name: &str, lines: &[String], ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.text(commands::command_reply(room_id, name, lines));
+    }
 }
 
 impl Actor for WsChatSession {
@@ -57,15 +394,25 @@ impl Actor for WsChatSession {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         self.hb(ctx);
+        tracing::info!(parent: &self.span, "Session started");
 
-        let addr = ctx.address();
+        let sender = outbound::SessionSender::new(ctx.address().recipient(), self.outbound);
+        self.sender = Some(sender.clone());
 
+        // The server answers with the lobby as a `Notice` once the session has its id
         self.addr
-            .send(server::Connect { addr: addr.recipient(), })
+            .send(server::Connect {
+                addr: sender,
+                notices: ctx.address().recipient(),
+                user_id: self.name.clone(),
+            })
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
-                    Ok(res) => act.id = res,
+                    Ok(res) => {
+                        act.id = res;
+                        act.span.record("session_id", res);
+                    }
                     _ => ctx.stop(),
                 }
 
@@ -76,23 +423,63 @@ impl Actor for WsChatSession {
 
     fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
         self.addr.do_send(server::Disconnect { id: self.id });
+        tracing::info!(parent: &self.span, "Session ended");
         Running::Stop
     }
 }
 
-impl Handler<server::Message> for WsChatSession {
+impl Handler<server::Notice> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: server::Notice, ctx: &mut Self::Context) -> Self::Result {
+        if let server::Notice::Moved(room) = msg {
+            let (room, room_addr) = room.unzip();
+            self.span.record("room", room.as_deref().unwrap_or_default());
+            self.room = room;
+            self.room_addr = room_addr;
+        }
+
+        self.load_restrictions(ctx);
+    }
+}
+
+impl Handler<outbound::Drain> for WsChatSession {
     type Result = ();
 
-    fn handle(&mut self, msg: server::Message, ctx: &mut Self::Context) -> Self::Result {
-        ctx.text(msg.0);
+    fn handle(&mut self, _msg: outbound::Drain, ctx: &mut Self::Context) -> Self::Result {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+
+        let drained = sender.drain();
+        for frame in drained.frames {
+            ctx.text(frame.payload);
+        }
+
+        if drained.evicted {
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some("Too many undelivered messages".to_owned()),
+            }));
+            ctx.stop();
+        } else if drained.closing {
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Restart,
+                description: Some("Server restarting".to_owned()),
+            }));
+            ctx.stop();
+        }
     }
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
     fn handle(&mut self, item: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let _span = self.span.clone().entered();
+
         let msg = match item {
             Ok(msg) => msg,
-            Err(_) => {
+            Err(err) => {
+                tracing::debug!(error = %err, "Protocol error");
                 ctx.stop();
                 return;
             }
@@ -109,72 +496,52 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
             }
 
             ws::Message::Text(text) => {
-                let data_json = serde_json::from_str::<ChatMessage>(&text.to_string());
-                if let Err(err) = data_json {
-                    println!("{err} Failed to parse message: {text}");
+                let input = match serde_json::from_str::<ChatMessage>(&text) {
+                    Ok(input) => input,
+                    Err(err) => {
+                        tracing::debug!(error = %err, text = %text, "Failed to parse message");
+                        return;
+                    }
+                };
+
+                let kind = match input.chat_type {
+                    ChatType::TEXT | ChatType::MODERATE => flood::FrameKind::Text,
+                    ChatType::TYPING => flood::FrameKind::Typing,
+                    _ => return,
+                };
+
+                // Who is speaking comes from the handshake, the `user_id` a frame carries is ignored
+                let Some(user_id) = self.name.clone() else {
+                    if kind == flood::FrameKind::Text {
+                        self.send_error(&input.room_id, "Connect with an access token to chat", ctx);
+                    }
+                    return;
+                };
+
+                if !self.allow(&user_id, &input, kind, ctx) {
                     return;
                 }
 
-                let input = data_json.as_ref().unwrap();
-                match &input.chat_type {
+                match input.chat_type {
                     ChatType::TYPING => {
-                        let chat_msg = ChatMessage {
-                            chat_type: ChatType::TYPING,
-                            value: input.value.to_vec(),
-                            room_id: input.room_id.to_string(),
-                            user_id: input.user_id.to_string(),
-                            id: self.id,
+                        let Some(room_addr) = &self.room_addr else {
+                            return;
                         };
 
-                        let msg = serde_json::to_string(&chat_msg).unwrap();
-
-                        self.addr.do_send(server::ClientMessage {
+                        room_addr.do_send(room::Typing {
                             id: self.id,
-                            msg,
-                            room: self.room.clone(),
+                            user_id: user_id.clone(),
+                            active: input.value.first().map(String::as_str) != Some("OUT"),
                         });
                     }
 
-                    ChatType::TEXT => {
-                        let chat_msg = ChatMessage {
-                            chat_type: ChatType::TEXT,
-                            value: input.value.to_vec(),
-                            room_id: input.room_id.to_string(),
-                            user_id: input.user_id.to_string(),
-                            id: self.id,
-                        };
-
-                        let new_conversation = models::NewConversation {
-                            user_id: input.user_id.to_string(),
-                            room_id: input.room_id.to_string(),
-                            message: input.value.join(""),
-                        };
-
-                        let msg = serde_json::to_string(&chat_msg).unwrap();
-
-                        self.addr.do_send(server::ClientMessage {
-                            id: self.id,
-                            msg,
-                            room: self.room.clone(),
-                        });
-
-                        
-                        let temp = self.db.clone();
-
-                        let future = async move {
-                            let _ = temp.add_conversation(new_conversation).await;
-                        };
-
-                        let future = actix::fut::wrap_future::<_, Self>(future);
-                        ctx.spawn(future);
-                        
-                    }
+                    ChatType::TEXT | ChatType::MODERATE => self.dispatch(user_id, input, ctx),
 
                     _ => {}
                 }
             }
 
-            ws::Message::Binary(_) => println!("Unsupported binary"),
+            ws::Message::Binary(_) => tracing::debug!("Unsupported binary frame"),
 
             ws::Message::Close(reason) => {
                 ctx.close(reason);
@@ -188,4 +555,67 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
             ws::Message::Nop => (),
         }
     }
-}
\ No newline at end of file
+}
+
+/// Reads a duration in seconds from a moderation command, where a missing value or `0` means permanent
+fn parse_duration(arg: Option<&String>) -> Result<Option<Duration>, moderation::ModerationError> {
+    let Some(arg) = arg else {
+        return Ok(None);
+    };
+
+    match arg.parse::<u64>() {
+        Ok(0) => Ok(None),
+        Ok(secs) => Ok(Some(Duration::from_secs(secs))),
+        Err(_) => Err(moderation::ModerationError::Invalid(format!("{arg} is not a number of seconds"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    /// A sanction on alice in `main` that expires `expires_in` seconds from now, never if `None`
+    fn sanction(kind: models::SanctionKind, expires_in: Option<i64>) -> models::Sanction {
+        models::Sanction {
+            id: None,
+            user_id: "alice".to_owned(),
+            room_id: Some("main".to_owned()),
+            kind,
+            issued_by: "bob".to_owned(),
+            created_at: SystemTime::now().into(),
+            expires_at: expires_in.map(|secs| bson::DateTime::from_millis(bson::DateTime::now().timestamp_millis() + secs * 1000)),
+        }
+    }
+
+    #[test]
+    fn nothing_blocks_without_sanctions() {
+        assert!(Restrictions::default().blocking().is_none());
+    }
+
+    #[test]
+    fn bans_block_before_mutes() {
+        let restrictions = Restrictions {
+            sanctions: vec![sanction(models::SanctionKind::Mute, None), sanction(models::SanctionKind::Ban, Some(60))],
+            ..Default::default()
+        };
+
+        assert_eq!(restrictions.blocking().map(|sanction| sanction.kind), Some(models::SanctionKind::Ban));
+    }
+
+    #[test]
+    fn expired_sanctions_stop_blocking() {
+        let restrictions = Restrictions {
+            sanctions: vec![sanction(models::SanctionKind::Ban, Some(-1)), sanction(models::SanctionKind::Mute, Some(60))],
+            ..Default::default()
+        };
+        assert_eq!(restrictions.blocking().map(|sanction| sanction.kind), Some(models::SanctionKind::Mute));
+
+        let restrictions = Restrictions {
+            sanctions: vec![sanction(models::SanctionKind::Mute, Some(-1))],
+            ..Default::default()
+        };
+        assert!(restrictions.blocking().is_none());
+    }
+}
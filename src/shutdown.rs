@@ -0,0 +1,70 @@
+use std::time::{Duration, Instant};
+
+use actix::Addr;
+use actix_web::dev::ServerHandle;
+
+use crate::{persistence, server};
+
+/// How often the number of open sessions is checked while they close
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Share of the shutdown deadline sessions may use to close, the rest is kept for the flush
+const SESSION_SHARE: u32 = 2;
+
+/// Resolves once the process is asked to stop with SIGTERM or Ctrl-C
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use actix_rt::signal::unix::{signal, SignalKind};
+
+        let Ok(mut term) = signal(SignalKind::terminate()) else {
+            let _ = actix_rt::signal::ctrl_c().await;
+            return;
+        };
+
+        futures::future::select(Box::pin(term.recv()), Box::pin(actix_rt::signal::ctrl_c())).await;
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = actix_rt::signal::ctrl_c().await;
+    }
+}
+
+/// Takes the node out of service without losing messages.
+///
+/// New connections are refused first, then every session is closed with a "server restarting"
+/// frame. Sessions get at most `1 / SESSION_SHARE` of `deadline` to go away, then buffered
+/// messages are written to the database with the time that is left. Whatever the flush does not
+/// manage to write is moved to the outbox before the HTTP workers are stopped.
+pub async fn drain(http: ServerHandle, chat: Addr<server::ChatServer>, writer: Addr<persistence::ConversationWriter>, deadline: Duration) {
+    let started = Instant::now();
+    let until = started + deadline;
+    let sessions_until = started + deadline / SESSION_SHARE;
+
+    http.pause().await;
+
+    let sessions = chat.send(server::Shutdown).await.unwrap_or_default();
+    tracing::info!(sessions, "Shutting down, closing sessions");
+
+    while Instant::now() < sessions_until {
+        match chat.send(server::Stats).await {
+            Ok(stats) if stats.sessions > 0 => actix_rt::time::sleep(DRAIN_POLL_INTERVAL).await,
+            _ => break,
+        }
+    }
+
+    // Sessions hand their last messages to the writer as they stop, so it is flushed after them
+    let remaining = until.saturating_duration_since(Instant::now());
+    match actix_rt::time::timeout(remaining, writer.send(persistence::Flush)).await {
+        Ok(Ok(())) => tracing::info!("Flushed pending messages"),
+        Ok(Err(err)) => tracing::error!(error = %err, "Failed to flush pending messages"),
+        Err(_) => {
+            tracing::error!("Timed out flushing pending messages");
+            if let Err(err) = writer.send(persistence::Abandon).await {
+                tracing::error!(error = %err, "Failed to move pending messages to the outbox");
+            }
+        }
+    }
+
+    http.stop(true).await;
+}
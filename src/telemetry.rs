@@ -0,0 +1,24 @@
+use std::env;
+
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+const LOG_FORMAT_KEY: &str = "LOG_FORMAT";
+const DEFAULT_FILTER: &str = "info";
+
+/// Installs the global tracing subscriber.
+///
+/// Logs are written as JSON lines unless `LOG_FORMAT=text`, and filtered with the usual
+/// `RUST_LOG` directives such as `info,realtime_chatrooms::database=debug`. Spans log once when
+/// they close, so every HTTP request, session and database call gets a line with its timings.
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE);
+
+    match env::var(LOG_FORMAT_KEY).as_deref() {
+        Ok("text") => builder.init(),
+        _ => builder.json().with_current_span(true).with_span_list(true).init(),
+    }
+}
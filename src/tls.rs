@@ -0,0 +1,201 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header;
+use actix_web::{Error, HttpResponse};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+
+/// Why a certificate or key could not be loaded
+#[derive(Debug)]
+pub enum TlsError {
+    Read(PathBuf, io::Error),
+    NoCertificates(PathBuf),
+    NoKey(PathBuf),
+    UnsupportedKey(PathBuf),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Read(path, err) => write!(f, "Failed to read {}: {err}", path.display()),
+            TlsError::NoCertificates(path) => write!(f, "No PEM certificates found in {}", path.display()),
+            TlsError::NoKey(path) => write!(f, "No PEM private key found in {}", path.display()),
+            TlsError::UnsupportedKey(path) => write!(f, "The private key in {} is not a supported type", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+/// Serves the certificate loaded from disk, swapping it for the new one when the files change.
+///
+/// Handshakes in progress keep the certificate they started with, so a renewal never drops
+/// connections. A renewal that fails to load is logged and the previous certificate stays in use.
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+    /// Modification times of the certificate and key when they were last loaded
+    loaded: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl CertResolver {
+    pub fn new(cert_path: &Path, key_path: &Path) -> Result<Self, TlsError> {
+        let loaded = (modified(cert_path), modified(key_path));
+
+        Ok(CertResolver {
+            current: RwLock::new(Arc::new(load(cert_path, key_path)?)),
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+            loaded: Mutex::new(loaded),
+        })
+    }
+
+    /// A rustls configuration answering every handshake with the current certificate
+    pub fn server_config(self: &Arc<Self>) -> ServerConfig {
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone())
+    }
+
+    /// Loads the certificate again if either file has changed, returning whether it was replaced
+    pub fn reload_if_changed(&self) -> Result<bool, TlsError> {
+        let now = (modified(&self.cert_path), modified(&self.key_path));
+        let mut loaded = self.loaded.lock().unwrap();
+
+        if *loaded == now {
+            return Ok(false);
+        }
+
+        // The times are only recorded once loading succeeds, so a half written renewal is retried
+        let key = load(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        *loaded = now;
+
+        Ok(true)
+    }
+
+    /// Checks the files every `interval` for as long as the process runs
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        loop {
+            actix_rt::time::sleep(interval).await;
+
+            match self.reload_if_changed() {
+                Ok(true) => tracing::info!(cert = %self.cert_path.display(), "Reloaded TLS certificate"),
+                Ok(false) => {}
+                Err(err) => tracing::error!(error = %err, "Failed to reload TLS certificate, keeping the previous one"),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Reads a PEM certificate chain and the private key it was issued for
+fn load(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, TlsError> {
+    let certs = File::open(cert_path)
+        .and_then(|file| rustls_pemfile::certs(&mut BufReader::new(file)))
+        .map_err(|err| TlsError::Read(cert_path.to_owned(), err))?;
+
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(cert_path.to_owned()));
+    }
+
+    let items = File::open(key_path)
+        .and_then(|file| rustls_pemfile::read_all(&mut BufReader::new(file)))
+        .map_err(|err| TlsError::Read(key_path.to_owned(), err))?;
+
+    let key = items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| TlsError::NoKey(key_path.to_owned()))?;
+
+    let signing_key = sign::any_supported_type(&key).map_err(|_| TlsError::UnsupportedKey(key_path.to_owned()))?;
+
+    Ok(CertifiedKey::new(certs.into_iter().map(Certificate).collect(), signing_key))
+}
+
+/// Middleware answering requests made over plain HTTP with a redirect to the same URL on HTTPS
+#[derive(Clone)]
+pub struct RedirectHttps {
+    /// Port HTTPS is served on, left out of the URL when it is 443
+    pub port: u16,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RedirectHttps
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RedirectHttpsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RedirectHttpsMiddleware { service, port: self.port }))
+    }
+}
+
+pub struct RedirectHttpsMiddleware<S> {
+    service: S,
+    port: u16,
+}
+
+impl<S, B> Service<ServiceRequest> for RedirectHttpsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if req.app_config().secure() {
+            let fut = self.service.call(req);
+            return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+        }
+
+        let host = req.connection_info().host().to_owned();
+        // Strip the plain HTTP port, keeping IPv6 literals such as [::1] intact
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name.to_owned(),
+            _ => host,
+        };
+        let port = if self.port == 443 { String::new() } else { format!(":{}", self.port) };
+        let path = req.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+
+        let res = HttpResponse::PermanentRedirect()
+            .insert_header((header::LOCATION, format!("https://{host}{port}{path}")))
+            .finish();
+
+        Box::pin(ready(Ok(req.into_response(res).map_into_right_body())))
+    }
+}